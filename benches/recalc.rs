@@ -0,0 +1,120 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rayon::ThreadPoolBuilder;
+
+use tabcel::table::{Operation, Table};
+
+/// (1, 1) = 0, (1, y) = (1, y - 1) + 1
+fn chain(len: u32) -> Table {
+    let mut table = Table::new();
+
+    table.insert((1, 1), Operation::Value(0.)).unwrap();
+    for y in 2..=len {
+        table
+            .insert(
+                (1, y),
+                Operation::Add(
+                    Box::new(Operation::Cell((1, y - 1))),
+                    Box::new(Operation::Value(1.)),
+                ),
+            )
+            .unwrap();
+    }
+
+    table
+}
+
+/// (1, 1) = 0, (2, y) = (1, 1) + (2, y - 1), i.e. every cell is reachable on two paths
+fn diamonds(len: u32) -> Table {
+    let mut table = Table::new();
+
+    table.insert((1, 1), Operation::Value(0.)).unwrap();
+    table.insert((2, 1), Operation::Cell((1, 1))).unwrap();
+    for y in 2..=len {
+        table
+            .insert(
+                (2, y),
+                Operation::Add(
+                    Box::new(Operation::Cell((1, 1))),
+                    Box::new(Operation::Cell((2, y - 1))),
+                ),
+            )
+            .unwrap();
+    }
+
+    table
+}
+
+/// (1, 1) = 0, (x, 2) = (1, 1) * x, (x, 3) = (x, 2) + (1, 1), i.e. two levels of independent cells
+fn wide(width: u32) -> Table {
+    let mut table = Table::new();
+
+    table.insert((1, 1), Operation::Value(0.)).unwrap();
+    for x in 1..=width {
+        table
+            .insert(
+                (x, 2),
+                Operation::Mul(
+                    Box::new(Operation::Cell((1, 1))),
+                    Box::new(Operation::Value(x as f64)),
+                ),
+            )
+            .unwrap();
+        table
+            .insert(
+                (x, 3),
+                Operation::Add(
+                    Box::new(Operation::Cell((x, 2))),
+                    Box::new(Operation::Cell((1, 1))),
+                ),
+            )
+            .unwrap();
+    }
+
+    table
+}
+
+fn recalc(c: &mut Criterion) {
+    let mut group = c.benchmark_group("recalc");
+    group.sample_size(10);
+
+    for len in [1_000, 10_000, 100_000].iter() {
+        group.throughput(Throughput::Elements(*len as u64));
+
+        let mut table = chain(*len);
+        group.bench_with_input(BenchmarkId::new("chain", len), len, |b, _| {
+            b.iter(|| table.insert((1, 1), Operation::Value(1.)).unwrap())
+        });
+
+        let mut table = diamonds(*len);
+        group.bench_with_input(BenchmarkId::new("diamonds", len), len, |b, _| {
+            b.iter(|| table.insert((1, 1), Operation::Value(1.)).unwrap())
+        });
+    }
+
+    group.finish();
+}
+
+/// Wide sheets on all cores compared to a single thread
+fn recalc_wide(c: &mut Criterion) {
+    let mut group = c.benchmark_group("recalc_wide");
+    group.sample_size(10);
+
+    let single = ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+
+    for width in [10_000, 100_000].iter() {
+        group.throughput(Throughput::Elements(2 * *width as u64));
+
+        let mut table = wide(*width);
+        group.bench_with_input(BenchmarkId::new("parallel", width), width, |b, _| {
+            b.iter(|| table.insert((1, 1), Operation::Value(1.)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("single_thread", width), width, |b, _| {
+            b.iter(|| single.install(|| table.insert((1, 1), Operation::Value(1.)).unwrap()))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, recalc, recalc_wide);
+criterion_main!(benches);
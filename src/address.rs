@@ -0,0 +1,210 @@
+//! Cell addresses in A1 notation, e.g. `B3`, `AA10` or `$B$3`
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::table::Coord;
+
+/// Address of a single cell, with optional `$` markers for absolute columns and rows
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Address {
+    /// column, starting at 1 for `A`
+    pub col: u32,
+    /// row, starting at 1
+    pub row: u32,
+    pub col_absolute: bool,
+    pub row_absolute: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AddressError {
+    pub input: String,
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid cell address '{}'", self.input)
+    }
+}
+
+impl std::error::Error for AddressError {}
+
+impl Address {
+    /// Relative address of the cell at `coord`
+    pub fn new(coord: Coord) -> Address {
+        Address {
+            col: coord.0,
+            row: coord.1,
+            col_absolute: false,
+            row_absolute: false,
+        }
+    }
+
+    pub fn coord(&self) -> Coord {
+        (self.col, self.row)
+    }
+}
+
+impl From<Coord> for Address {
+    fn from(coord: Coord) -> Address {
+        Address::new(coord)
+    }
+}
+
+impl From<Address> for Coord {
+    fn from(address: Address) -> Coord {
+        address.coord()
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Address, AddressError> {
+        let err = || AddressError {
+            input: s.to_string(),
+        };
+
+        let (col_absolute, rest) = match s.strip_prefix('$') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+        let split = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .ok_or_else(err)?;
+        let (letters, rest) = rest.split_at(split);
+        let (row_absolute, digits) = match rest.strip_prefix('$') {
+            Some(digits) => (true, digits),
+            None => (false, rest),
+        };
+
+        if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(err());
+        }
+
+        let col = column_number(letters).ok_or_else(err)?;
+        let row = digits.parse::<u32>().map_err(|_| err())?;
+        if row == 0 {
+            return Err(err());
+        }
+
+        Ok(Address {
+            col,
+            row,
+            col_absolute,
+            row_absolute,
+        })
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.col_absolute {
+            write!(f, "$")?;
+        }
+        write!(f, "{}", column_name(self.col))?;
+        if self.row_absolute {
+            write!(f, "$")?;
+        }
+        write!(f, "{}", self.row)
+    }
+}
+
+/// Letters of column `col`, i.e. 1 is `A`, 26 is `Z` and 27 is `AA`
+/// Column 0 has no name and results in an empty string
+pub fn column_name(col: u32) -> String {
+    let mut name = vec![];
+    let mut n = col;
+
+    while n > 0 {
+        let rem = (n - 1) % 26;
+        name.push((b'A' + rem as u8) as char);
+        n = (n - 1) / 26;
+    }
+
+    name.iter().rev().collect()
+}
+
+/// Column of the letters `name` (case insensitive), i.e. `A` is 1 and `AA` is 27
+pub fn column_number(name: &str) -> Option<u32> {
+    if name.is_empty() {
+        return None;
+    }
+
+    let mut col: u32 = 0;
+    for c in name.chars() {
+        if !c.is_ascii_alphabetic() {
+            return None;
+        }
+        col = col
+            .checked_mul(26)?
+            .checked_add(c.to_ascii_uppercase() as u32 - 'A' as u32 + 1)?;
+    }
+
+    Some(col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn column_names() {
+        assert_eq!(column_name(1), "A");
+        assert_eq!(column_name(26), "Z");
+        assert_eq!(column_name(27), "AA");
+        assert_eq!(column_name(52), "AZ");
+        assert_eq!(column_name(703), "AAA");
+        assert_eq!(column_name(0), "");
+
+        for col in 1..2000 {
+            assert_eq!(column_number(&column_name(col)), Some(col));
+        }
+        assert_eq!(column_number("ab"), Some(28));
+        assert_eq!(column_number(""), None);
+        assert_eq!(column_number("A1"), None);
+    }
+
+    #[test]
+    fn parse_addresses() {
+        assert_eq!("A1".parse(), Ok(Address::new((1, 1))));
+        assert_eq!("aa10".parse::<Address>().map(|a| a.coord()), Ok((27, 10)));
+        assert_eq!(
+            "$B$3".parse(),
+            Ok(Address {
+                col: 2,
+                row: 3,
+                col_absolute: true,
+                row_absolute: true
+            })
+        );
+        assert_eq!(
+            "B$3".parse(),
+            Ok(Address {
+                col: 2,
+                row: 3,
+                col_absolute: false,
+                row_absolute: true
+            })
+        );
+
+        for invalid in &[
+            "", "A", "1", "A0", "1A", "A1B", "$$A1", "A$$1", "A-1", "A1$",
+        ] {
+            assert_eq!(
+                invalid.parse::<Address>(),
+                Err(AddressError {
+                    input: invalid.to_string()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn format_addresses() {
+        assert_eq!(Address::new((28, 5)).to_string(), "AB5");
+        assert_eq!("$c$7".parse::<Address>().unwrap().to_string(), "$C$7");
+        assert_eq!(Address::from((3, 7)).to_string(), "C7");
+        assert_eq!(Coord::from(Address::new((3, 7))), (3, 7));
+    }
+}
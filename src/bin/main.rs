@@ -1,12 +1,21 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::{error::Error, io};
+use tabcel::address::{column_name, Address};
+use tabcel::cell::CellValue;
+use tabcel::csv::{self, CsvOptions};
+use tabcel::format::Format;
+use tabcel::parser::{parse_input, to_input};
+use tabcel::table::{Coord, Table};
 use termion::{event::Key, input::MouseTerminal, raw::IntoRawMode, screen::AlternateScreen};
 use tui::{
-    backend::TermionBackend,
-    layout::{Constraint, Direction, Layout, Rect},
+    backend::{Backend, TermionBackend},
+    layout::{Alignment, Rect},
     style::{Color, Modifier, Style},
     text::{Span, Spans},
-    widgets::{Block, Borders, Gauge, Paragraph, Row, Table, TableState},
-    Terminal,
+    widgets::{Block, Borders, Paragraph},
+    Frame, Terminal,
 };
 
 use std::sync::mpsc;
@@ -24,8 +33,8 @@ pub enum Event<I> {
 /// type is handled in its own thread and returned to a common `Receiver`
 pub struct Events {
     rx: mpsc::Receiver<Event<Key>>,
-    input_handle: thread::JoinHandle<()>,
-    tick_handle: thread::JoinHandle<()>,
+    _input_handle: thread::JoinHandle<()>,
+    _tick_handle: thread::JoinHandle<()>,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -41,6 +50,12 @@ impl Default for Config {
     }
 }
 
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Events {
     pub fn new() -> Events {
         Events::with_config(Config::default())
@@ -52,12 +67,10 @@ impl Events {
             let tx = tx.clone();
             thread::spawn(move || {
                 let stdin = io::stdin();
-                for evt in stdin.keys() {
-                    if let Ok(key) = evt {
-                        if let Err(err) = tx.send(Event::Input(key)) {
-                            eprintln!("{}", err);
-                            return;
-                        }
+                for key in stdin.keys().flatten() {
+                    if let Err(err) = tx.send(Event::Input(key)) {
+                        eprintln!("{}", err);
+                        return;
                     }
                 }
             })
@@ -73,8 +86,8 @@ impl Events {
         };
         Events {
             rx,
-            input_handle,
-            tick_handle,
+            _input_handle: input_handle,
+            _tick_handle: tick_handle,
         }
     }
 
@@ -83,6 +96,7 @@ impl Events {
     }
 }
 
+/// The selected cell
 struct Selected {
     x: u32,
     y: u32,
@@ -94,23 +108,23 @@ impl Selected {
     }
 
     fn up(&mut self) {
-        if self.y != 0 {
+        if self.y > 1 {
             self.y -= 1;
         }
     }
 
     fn down(&mut self) {
-        self.y += 1;
+        self.y = self.y.saturating_add(1);
     }
 
     fn left(&mut self) {
-        if self.x != 0 {
+        if self.x > 1 {
             self.x -= 1;
         }
     }
 
     fn right(&mut self) {
-        self.x += 1;
+        self.x = self.x.saturating_add(1);
     }
 
     fn is_selected(&self, x: u32, y: u32) -> bool {
@@ -120,233 +134,839 @@ impl Selected {
 
         false
     }
-}
 
-fn main() -> Result<(), Box<dyn Error>> {
-    // Terminal initialization
-    let stdout = io::stdout().into_raw_mode()?;
-    let stdout = MouseTerminal::from(stdout);
-    let stdout = AlternateScreen::from(stdout);
-    let backend = TermionBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+    /// Move `rows` rows up, but not past the first row
+    fn page_up(&mut self, rows: u32) {
+        self.y = self.y.saturating_sub(rows).max(1);
+    }
 
-    let events = Events::with_config(Config {
-        tick_rate: Duration::from_millis(100),
-    });
+    /// Move `rows` rows down, but not past the last row
+    fn page_down(&mut self, rows: u32) {
+        self.y = self.y.saturating_add(rows);
+    }
+
+    fn jump(&mut self, coord: Coord) {
+        self.x = coord.0;
+        self.y = coord.1;
+    }
 
-    const CELL_WIDTH: u16 = 15;
-    const CELL_HEIGHT: u16 = 3;
+    fn coord(&self) -> Coord {
+        (self.x, self.y)
+    }
+}
 
-    let mut selected = Selected::new(5, 5);
+/// The part of the table which is on the screen
+struct Viewport {
+    /// first visible column
+    col: u32,
+    /// first visible row
+    row: u32,
+    /// number of visible columns, as of the last draw
+    cols: u32,
+    /// number of visible rows, as of the last draw
+    rows: u32,
+}
 
-    loop {
-        terminal.draw(|f| {
-            // header
-            let mut header_rects = vec![];
-            let mut header_texts = vec![];
-            for x in 1..50 {
-                if (CELL_WIDTH - 1) * x + CELL_WIDTH < f.size().width {
-                    header_rects.push(Rect::new(
-                        (CELL_WIDTH - 1) * x,
-                        CELL_HEIGHT - 1,
-                        CELL_WIDTH,
-                        CELL_HEIGHT,
-                    ));
-                    header_texts.push(
-                        Paragraph::new(vec![Spans::from(Span::from(format!("Col {}", x)))])
-                            .style(
-                                Style::default()
-                                    .fg(Color::White)
-                                    .add_modifier(Modifier::BOLD),
-                            )
-                            .block(
-                                Block::default().borders(Borders::ALL).border_style(
-                                    Style::default()
-                                        .fg(Color::White)
-                                        .add_modifier(Modifier::BOLD),
-                                ),
-                            ),
-                    );
+impl Viewport {
+    fn new() -> Viewport {
+        Viewport {
+            col: 1,
+            row: 1,
+            cols: 1,
+            rows: 1,
+        }
+    }
+
+    /// Scroll as little as possible so that `row` is visible, without scrolling past the last row
+    fn follow_row(&mut self, row: u32) {
+        let len = self.rows.max(1);
+        if row < self.row {
+            self.row = row;
+        } else if row - self.row >= len {
+            self.row = row - (len - 1);
+        }
+        self.row = self.row.min(u32::MAX - (len - 1));
+    }
+
+    /// Scroll as little as possible so that column `col` is visible on a screen of width `width`.
+    /// Columns can have different widths, so this also updates `cols`
+    fn follow_column(&mut self, col: u32, table: &Table, width: u16) {
+        if col < self.col {
+            self.col = col;
+        } else if col - self.col >= column_layout(table, self.col, width).len() as u32 {
+            // make `col` the last visible column
+            self.col = col;
+            let mut end = (CELL_WIDTH - 1) as u32 + column_width(table, col) as u32;
+            while self.col > 1 {
+                let w = column_width(table, self.col - 1) as u32 - 1;
+                if end + w >= width as u32 {
+                    break;
                 }
+                end += w;
+                self.col -= 1;
             }
-            for c in &header_rects {
-                f.render_widget(header_texts.remove(0), *c);
+        }
+
+        self.cols = column_layout(table, self.col, width).len() as u32;
+    }
+}
+
+enum Mode {
+    /// moving around the table
+    Normal,
+    /// editing the selected cell, holds the input typed so far
+    Editing(String),
+    /// typing the address of the cell to jump to
+    Goto(String),
+    /// typing a command, e.g. `w file.tc`. See `App::execute`
+    Command(String),
+}
+
+/// How files are read and written. Files ending in `.csv` or `.tsv` hold the values of cells
+/// as delimited text, all others are in the native format, see `tabcel::file`
+struct FileOptions {
+    /// delimiter of CSV files, if it's not the default of the extension
+    delimiter: Option<char>,
+    /// the first record of CSV files is a header
+    header: bool,
+}
+
+impl FileOptions {
+    /// Options to read or write `path` as delimited text. `None` for files in the native format
+    fn csv(&self, path: &Path) -> Option<CsvOptions> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        let default = match extension.as_str() {
+            "csv" => CsvOptions::default(),
+            "tsv" => CsvOptions::tsv(),
+            _ => return None,
+        };
+
+        Some(CsvOptions {
+            delimiter: self.delimiter.unwrap_or(default.delimiter),
+            header: self.header,
+            ..default
+        })
+    }
+
+    fn load(&self, path: &Path) -> Result<Table, Box<dyn Error>> {
+        match self.csv(path) {
+            Some(options) => {
+                let mut table = Table::new();
+                csv::import(&mut table, File::open(path)?, (1, 1), &options)?;
+                Ok(table)
             }
+            None => Ok(Table::load(path)?),
+        }
+    }
 
-            // row numbering
-            let mut rows_rects = vec![];
-            let mut rows_texts = vec![];
-            for y in 2..50 {
-                if (CELL_HEIGHT - 1) * y + CELL_HEIGHT < f.size().height - 2 * CELL_HEIGHT {
-                    rows_rects.push(Rect::new(0, (CELL_HEIGHT - 1) * y, CELL_WIDTH, CELL_HEIGHT));
-                    rows_texts.push(
-                        Paragraph::new(vec![Spans::from(Span::from(format!("Row {}", y - 1)))])
-                            .style(
-                                Style::default()
-                                    .fg(Color::White)
-                                    .add_modifier(Modifier::BOLD),
-                            )
-                            .block(
-                                Block::default().borders(Borders::ALL).border_style(
-                                    Style::default()
-                                        .fg(Color::White)
-                                        .add_modifier(Modifier::BOLD),
-                                ),
-                            ),
-                    );
+    fn save(&self, table: &Table, path: &Path) -> Result<(), Box<dyn Error>> {
+        match self.csv(path) {
+            Some(options) => {
+                let mut writer = BufWriter::new(File::create(path)?);
+                csv::export(table, &mut writer, &options)?;
+                writer.flush()?;
+            }
+            None => table.save(path)?,
+        }
+        Ok(())
+    }
+}
+
+/// Shown in the status line until the next key press
+enum Message {
+    Info(String),
+    /// e.g. why the last input was rejected
+    Error(String),
+}
+
+struct App {
+    table: Table,
+    /// file the table is written to by `:w`
+    path: Option<PathBuf>,
+    files: FileOptions,
+    selected: Selected,
+    viewport: Viewport,
+    mode: Mode,
+    message: Option<Message>,
+    /// the table changed since it was last read or written
+    modified: bool,
+}
+
+impl App {
+    fn new(table: Table, path: Option<PathBuf>, files: FileOptions) -> App {
+        App {
+            table,
+            path,
+            files,
+            selected: Selected::new(1, 1),
+            viewport: Viewport::new(),
+            mode: Mode::Normal,
+            message: None,
+            modified: false,
+        }
+    }
+
+    /// Raw content of the selected cell as it was typed in
+    fn selected_input(&self) -> String {
+        self.table
+            .get_operation_of_cell(&self.selected.coord())
+            .map(to_input)
+            .unwrap_or_default()
+    }
+
+    /// Last column of `row` with content, or the first column if the row is empty
+    fn last_column(&self, row: u32) -> u32 {
+        self.table
+            .iter()
+            .filter(|(coord, cell)| coord.1 == row && !cell.is_empty())
+            .map(|(coord, _)| coord.0)
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Write `input` into the selected cell. Empty input clears the cell
+    fn commit(&mut self, input: &str) -> Result<(), Box<dyn Error>> {
+        let coord = self.selected.coord();
+        if input.trim().is_empty() {
+            self.table.remove(&coord);
+            return Ok(());
+        }
+
+        let operation = parse_input(input)?;
+        self.table.insert(coord, operation)?;
+        Ok(())
+    }
+
+    /// Execute the command line `command`. Returns false if the app should quit
+    ///
+    /// - `w [file]`: write the table to `file`, or the file it was read from.
+    ///   CSV and TSV files only get the values of the cells
+    /// - `e file`: replace the table with the one in `file`, `e! file` discards unsaved changes
+    /// - `q`: quit, `q!`: quit discarding unsaved changes, `wq`: write and quit
+    /// - `width [characters]`: set the width of the selected column, or reset it
+    /// - `format <format>`: set the format of the selected cell, e.g. `fixed 2`
+    /// - `insert [rows]`: insert empty rows above the selected cell, one by default
+    fn execute(&mut self, command: &str) -> Result<bool, Box<dyn Error>> {
+        let command = command.trim();
+        let (name, argument) = match command.find(' ') {
+            Some(i) => (&command[..i], command[i..].trim()),
+            None => (command, ""),
+        };
+
+        match name {
+            "w" | "wq" => {
+                if !argument.is_empty() {
+                    self.path = Some(PathBuf::from(argument));
+                }
+                let path = self.path.as_ref().ok_or("no file name")?;
+                self.files.save(&self.table, path)?;
+                self.modified = false;
+                self.message = Some(Message::Info(format!("written {}", path.display())));
+                return Ok(name == "w");
+            }
+            "e" | "e!" => {
+                if argument.is_empty() {
+                    return Err("no file name".into());
+                }
+                if name == "e" {
+                    self.check_saved("e!")?;
                 }
+                self.table = self.files.load(Path::new(argument))?;
+                self.path = Some(PathBuf::from(argument));
+                self.selected = Selected::new(1, 1);
+                self.modified = false;
+                return Ok(true);
             }
-            for c in &rows_rects {
-                f.render_widget(rows_texts.remove(0), *c);
+            "q" => {
+                self.check_saved("q!")?;
+                return Ok(false);
             }
+            "q!" => return Ok(false),
+            "width" => {
+                let width = match argument {
+                    "" => None,
+                    w => Some(
+                        w.parse::<u16>()
+                            .map_err(|_| format!("invalid width '{}'", w))?,
+                    ),
+                };
+                self.table.set_column_width(self.selected.x, width);
+            }
+            "format" => self
+                .table
+                .set_format(self.selected.coord(), argument.parse::<Format>()?),
+            "insert" => {
+                let count = match argument {
+                    "" => 1,
+                    n => n
+                        .parse::<u32>()
+                        .map_err(|_| format!("invalid number of rows '{}'", n))?,
+                };
+                self.table.insert_rows(self.selected.y, count)?;
+            }
+            _ => return Err(format!("unknown command '{}'", name).into()),
+        }
+
+        self.modified = true;
+        Ok(true)
+    }
+
+    /// Error if there are unsaved changes, which `force` would discard
+    fn check_saved(&self, force: &str) -> Result<(), String> {
+        if self.modified {
+            return Err(format!(
+                "unsaved changes, use :w to write them or :{} to discard them",
+                force
+            ));
+        }
+        Ok(())
+    }
+
+    /// Make the selected column `delta` characters wider
+    fn resize_column(&mut self, delta: i32) {
+        let col = self.selected.x;
+        let width = self
+            .table
+            .get_column_width(col)
+            .unwrap_or(DEFAULT_COLUMN_WIDTH) as i32
+            + delta;
+        let width = width.clamp(1, MAX_COLUMN_WIDTH as i32) as u16;
+
+        let width = if width == DEFAULT_COLUMN_WIDTH {
+            None
+        } else {
+            Some(width)
+        };
+        self.table.set_column_width(col, width);
+        self.modified = true;
+    }
 
-            // main body with cells
-            let mut rects = vec![];
-            let mut texts = vec![];
-            // TODO: something like `for y in table_size` or so, so that we can omit the if condition
-            for y in 2..50 {
-                for x in 1..50 {
-                    let mut s = f.size();
-                    s.height -= 2 * CELL_HEIGHT;
-                    // HEIGHT-1 to remove standard margin
-                    if (CELL_HEIGHT - 1) * y + CELL_HEIGHT < s.height
-                        && (CELL_WIDTH - 1) * x + CELL_WIDTH < s.width
-                    {
-                        rects.push(Rect::new(
-                            (CELL_WIDTH - 1) * x,
-                            (CELL_HEIGHT - 1) * y,
-                            CELL_WIDTH,
-                            CELL_HEIGHT,
-                        ));
-                        texts.push(
-                            Paragraph::new(vec![Spans::from(Span::from(format!("{}, {}", x, y)))])
-                                .block(Block::default().borders(Borders::ALL)),
-                        );
-
-                        if selected.is_selected(x as u32, y as u32) {
-                            let temp = texts
-                                .remove(texts.len() - 1)
-                                .block(
-                                    Block::default()
-                                        .borders(Borders::ALL)
-                                        .border_style(Style::default().fg(Color::Cyan)),
-                                )
-                                .style(Style::default().fg(Color::Cyan));
-                            texts.push(temp);
+    /// Handle a key press. Returns false if the app should quit
+    fn handle_key(&mut self, key: Key) -> bool {
+        match &mut self.mode {
+            Mode::Normal => {
+                self.message = None;
+                match key {
+                    Key::Char('q') => match self.check_saved("q!") {
+                        Ok(()) => return false,
+                        Err(err) => self.message = Some(Message::Error(err)),
+                    },
+                    // termion reports Enter as '\n'
+                    Key::Char('\n') => self.mode = Mode::Editing(self.selected_input()),
+                    Key::Up => self.selected.up(),
+                    Key::Down => self.selected.down(),
+                    Key::Left => self.selected.left(),
+                    Key::Right => self.selected.right(),
+                    Key::PageUp => {
+                        // keep the cursor at the same position on the screen
+                        self.selected.page_up(self.viewport.rows);
+                        self.viewport.row =
+                            self.viewport.row.saturating_sub(self.viewport.rows).max(1);
+                    }
+                    Key::PageDown => {
+                        self.selected.page_down(self.viewport.rows);
+                        self.viewport.row = self.viewport.row.saturating_add(self.viewport.rows);
+                    }
+                    Key::Home => self.selected.jump((1, self.selected.y)),
+                    Key::End => {
+                        let row = self.selected.y;
+                        self.selected.jump((self.last_column(row), row));
+                    }
+                    Key::Char('g') => self.mode = Mode::Goto(String::new()),
+                    Key::Char(':') => self.mode = Mode::Command(String::new()),
+                    Key::Char('u') => {
+                        let done = self.table.undo();
+                        self.modified |= done;
+                        self.message =
+                            (!done).then(|| Message::Info("nothing to undo".to_string()));
+                    }
+                    Key::Ctrl('r') => {
+                        let done = self.table.redo();
+                        self.modified |= done;
+                        self.message =
+                            (!done).then(|| Message::Info("nothing to redo".to_string()));
+                    }
+                    Key::Char('<') => self.resize_column(-1),
+                    Key::Char('>') => self.resize_column(1),
+                    Key::Backspace | Key::Delete => {
+                        let coord = self.selected.coord();
+                        self.modified |= self.table.get_operation_of_cell(&coord).is_some();
+                        self.table.remove(&coord);
+                    }
+                    // every other printable key starts editing with an empty cell.
+                    // Values starting with one of the keys above can be typed after Enter
+                    Key::Char(c) if !c.is_control() => self.mode = Mode::Editing(c.to_string()),
+                    _ => (),
+                }
+            }
+            Mode::Editing(input) => match key {
+                Key::Esc => self.mode = Mode::Normal,
+                Key::Char('\n') => {
+                    let input = input.clone();
+                    match self.commit(&input) {
+                        Ok(()) => {
+                            self.modified = true;
+                            self.mode = Mode::Normal;
+                            self.message = None;
+                            self.selected.down();
                         }
+                        // keep editing, so the input can be fixed
+                        Err(err) => self.message = Some(Message::Error(err.to_string())),
+                    }
+                }
+                Key::Backspace => {
+                    input.pop();
+                }
+                Key::Char(c) if !c.is_control() => input.push(c),
+                _ => (),
+            },
+            Mode::Goto(input) => match key {
+                Key::Esc => self.mode = Mode::Normal,
+                Key::Char('\n') => {
+                    match input.trim().parse::<Address>() {
+                        Ok(address) => self.selected.jump(address.coord()),
+                        Err(err) => self.message = Some(Message::Error(err.to_string())),
+                    }
+                    self.mode = Mode::Normal;
+                }
+                Key::Backspace => {
+                    input.pop();
+                }
+                Key::Char(c) if !c.is_control() => input.push(c),
+                _ => (),
+            },
+            Mode::Command(input) => match key {
+                Key::Esc => self.mode = Mode::Normal,
+                Key::Char('\n') => {
+                    let command = input.clone();
+                    self.mode = Mode::Normal;
+                    match self.execute(&command) {
+                        Ok(keep_running) => return keep_running,
+                        Err(err) => self.message = Some(Message::Error(err.to_string())),
                     }
                 }
+                Key::Backspace => {
+                    input.pop();
+                }
+                Key::Char(c) if !c.is_control() => input.push(c),
+                _ => (),
+            },
+        }
+
+        true
+    }
+}
+
+const CELL_WIDTH: u16 = 15;
+const CELL_HEIGHT: u16 = 3;
+const DEFAULT_COLUMN_WIDTH: u16 = CELL_WIDTH - 2;
+const MAX_COLUMN_WIDTH: u16 = 200;
+const GOTO_PROMPT: &str = "Go to cell: ";
+const COMMAND_PROMPT: &str = ":";
+
+/// Number of cells of size `cell` that fit into `size`, when the first one is the `first`th.
+/// Neighbouring cells share their border
+fn fitting_cells(size: u16, cell: u16, first: u16) -> u32 {
+    (first..)
+        .take_while(|i| (cell - 1) * i + cell < size)
+        .count() as u32
+}
+
+/// Width of column `col` on the screen, including its borders
+fn column_width(table: &Table, col: u32) -> u16 {
+    table
+        .get_column_width(col)
+        .unwrap_or(DEFAULT_COLUMN_WIDTH)
+        .saturating_add(2)
+}
+
+/// Horizontal position and width of the columns starting at `first` which fit into `width`.
+/// The first column starts right of the row numbering
+fn column_layout(table: &Table, first: u32, width: u16) -> Vec<(u16, u16)> {
+    let mut layout = vec![];
+    let mut x = CELL_WIDTH - 1;
+    for col in first..=u32::MAX {
+        let w = column_width(table, col);
+        if x as u32 + w as u32 >= width as u32 {
+            break;
+        }
+        layout.push((x, w));
+        x += w - 1;
+    }
+    layout
+}
+
+fn draw<B: Backend>(f: &mut Frame<B>, app: &mut App) {
+    let bold = Style::default()
+        .fg(Color::White)
+        .add_modifier(Modifier::BOLD);
+
+    // the grid starts with the headers in the second column and row.
+    // Leave some space at the bottom for the status line
+    let size = f.size();
+    app.viewport.rows = fitting_cells(size.height.saturating_sub(2 * CELL_HEIGHT), CELL_HEIGHT, 2);
+    app.viewport.follow_row(app.selected.y);
+    app.viewport
+        .follow_column(app.selected.x, &app.table, size.width);
+    let columns = column_layout(&app.table, app.viewport.col, size.width);
+
+    // formula bar with the raw content of the selected cell
+    let formula_rect = Rect::new(0, 0, f.size().width, CELL_HEIGHT);
+    let (formula, formula_style) = match &app.mode {
+        Mode::Normal => (app.selected_input(), Style::default()),
+        Mode::Editing(input) => (input.clone(), Style::default().fg(Color::Cyan)),
+        Mode::Goto(_) | Mode::Command(_) => (app.selected_input(), Style::default()),
+    };
+    let address = Address::new(app.selected.coord()).to_string();
+    if let Mode::Editing(input) = &app.mode {
+        f.set_cursor(
+            formula_rect.x + 1 + input.chars().count() as u16,
+            formula_rect.y + 1,
+        );
+    }
+    f.render_widget(
+        Paragraph::new(vec![Spans::from(Span::from(formula))])
+            .style(formula_style)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(Span::styled(address, bold)),
+            ),
+        formula_rect,
+    );
+
+    // header
+    let mut header_rects = vec![];
+    let mut header_texts = vec![];
+    for (i, (x, width)) in columns.iter().enumerate() {
+        header_rects.push(Rect::new(*x, CELL_HEIGHT - 1, *width, CELL_HEIGHT));
+        header_texts.push(
+            Paragraph::new(vec![Spans::from(Span::from(column_name(
+                app.viewport.col + i as u32,
+            )))])
+            .style(bold)
+            .block(Block::default().borders(Borders::ALL).border_style(bold)),
+        );
+    }
+    for c in &header_rects {
+        f.render_widget(header_texts.remove(0), *c);
+    }
+
+    // row numbering
+    let mut rows_rects = vec![];
+    let mut rows_texts = vec![];
+    for j in 0..app.viewport.rows {
+        rows_rects.push(Rect::new(
+            0,
+            (CELL_HEIGHT - 1) * (j as u16 + 2),
+            CELL_WIDTH,
+            CELL_HEIGHT,
+        ));
+        rows_texts.push(
+            Paragraph::new(vec![Spans::from(Span::from(format!(
+                "{}",
+                app.viewport.row + j
+            )))])
+            .style(bold)
+            .block(Block::default().borders(Borders::ALL).border_style(bold)),
+        );
+    }
+    for c in &rows_rects {
+        f.render_widget(rows_texts.remove(0), *c);
+    }
+
+    // main body with cells
+    let mut rects = vec![];
+    let mut texts = vec![];
+    for j in 0..app.viewport.rows {
+        for (i, (x, width)) in columns.iter().enumerate() {
+            let coord = (app.viewport.col + i as u32, app.viewport.row + j);
+            let value = app.table.get_value_of_cell(&coord);
+            let alignment = match value {
+                Some(CellValue::Num(_)) => Alignment::Right,
+                _ => Alignment::Left,
+            };
+            let format = app.table.get_format(&coord);
+            let text = value.map(|v| format.apply(&v)).unwrap_or_default();
+
+            rects.push(Rect::new(
+                *x,
+                (CELL_HEIGHT - 1) * (j as u16 + 2),
+                *width,
+                CELL_HEIGHT,
+            ));
+            texts.push(
+                Paragraph::new(vec![Spans::from(Span::from(text))])
+                    .alignment(alignment)
+                    .block(Block::default().borders(Borders::ALL)),
+            );
+
+            if app.selected.is_selected(coord.0, coord.1) {
+                let temp = texts
+                    .remove(texts.len() - 1)
+                    .block(
+                        Block::default()
+                            .borders(Borders::ALL)
+                            .border_style(Style::default().fg(Color::Cyan)),
+                    )
+                    .style(Style::default().fg(Color::Cyan));
+                texts.push(temp);
             }
-            for i in 0..rects.len() {
-                f.render_widget(texts.remove(0), rects[i]);
+        }
+    }
+    for c in &rects {
+        f.render_widget(texts.remove(0), *c);
+    }
+
+    // status line
+    let prompt = match &app.mode {
+        Mode::Goto(input) => Some((GOTO_PROMPT, input)),
+        Mode::Command(input) => Some((COMMAND_PROMPT, input)),
+        _ => None,
+    };
+    let status = match (prompt, &app.message, &app.mode) {
+        (Some((prompt, input)), _, _) => Span::from(format!("{}{}", prompt, input)),
+        (None, Some(Message::Info(message)), _) => Span::from(message.clone()),
+        (None, Some(Message::Error(message)), _) => {
+            Span::styled(message.clone(), Style::default().fg(Color::Red))
+        }
+        (None, None, Mode::Editing(_)) => Span::from("Enter: confirm  Esc: cancel"),
+        (None, None, _) => Span::from(
+            "Enter: edit  Del: clear  u/^R: undo/redo  g: go to cell  </>: column width  \
+             :w save  q: quit",
+        ),
+    };
+    f.render_widget(
+        Paragraph::new(vec![Spans::from(status)]),
+        Rect::new(0, size.height - 1, size.width, 1),
+    );
+    if let Some((prompt, input)) = prompt {
+        f.set_cursor(
+            (prompt.len() + input.chars().count()) as u16,
+            size.height - 1,
+        );
+    }
+}
+
+const USAGE: &str = "usage:
+    tabcel [options] [file]
+        edit file interactively
+    tabcel eval [options] <file> --cell <cell>... [--set <cell>=<input>]...
+        print the values of the given cells
+    tabcel recalc [options] <file> [-o <file>] [--set <cell>=<input>]...
+        write the recalculated table to the output file, or its values as CSV to stdout
+
+options:
+    --delimiter <char>  delimiter of CSV files, `\\t` for tabs
+    --header            the first record of CSV files is a header";
+
+enum Command {
+    /// edit the file interactively
+    Edit(Option<PathBuf>),
+    /// print the values of `cells`
+    Eval { path: PathBuf, cells: Vec<Coord> },
+    /// write the table to `output`, or its values to stdout
+    Recalc {
+        path: PathBuf,
+        output: Option<PathBuf>,
+    },
+}
+
+/// Command line arguments, see `USAGE`
+struct Args {
+    command: Command,
+    files: FileOptions,
+    /// cells set before `eval` and `recalc`, with their raw input
+    assignments: Vec<(Coord, String)>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Args, String> {
+    let mut files = FileOptions {
+        delimiter: None,
+        header: false,
+    };
+    let mut cells = vec![];
+    let mut assignments = vec![];
+    let mut output = None;
+    let mut positional = vec![];
+
+    let cell = |address: &str| {
+        address
+            .parse::<Address>()
+            .map(|a| a.coord())
+            .map_err(|e| e.to_string())
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("missing value for '{}'", arg));
+        match arg.as_str() {
+            "--delimiter" => {
+                let delimiter = value()?;
+                let mut chars = delimiter.chars();
+                files.delimiter = match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ if delimiter == "\\t" => Some('\t'),
+                    _ => return Err(format!("invalid delimiter '{}'", delimiter)),
+                };
+            }
+            "--header" => files.header = true,
+            "--cell" => cells.push(cell(&value()?)?),
+            "--set" => {
+                let assignment = value()?;
+                let (address, input) = assignment
+                    .split_once('=')
+                    .ok_or(format!("invalid assignment '{}'", assignment))?;
+                assignments.push((cell(address)?, input.to_string()));
             }
-        })?;
+            "-o" | "--output" => output = Some(PathBuf::from(value()?)),
+            a if a.starts_with('-') => return Err(format!("unknown option '{}'", a)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let command = match positional.next() {
+        Some(c) if c == "eval" || c == "recalc" => {
+            let path = PathBuf::from(positional.next().ok_or("missing file")?);
+            if c == "eval" {
+                if cells.is_empty() {
+                    return Err("missing --cell".to_string());
+                }
+                Command::Eval {
+                    path,
+                    cells: std::mem::take(&mut cells),
+                }
+            } else {
+                Command::Recalc {
+                    path,
+                    output: output.take(),
+                }
+            }
+        }
+        path => {
+            if !assignments.is_empty() {
+                return Err("--set only works with eval and recalc".to_string());
+            }
+            Command::Edit(path.map(PathBuf::from))
+        }
+    };
+
+    if let Some(arg) = positional.next() {
+        return Err(format!("unexpected argument '{}'", arg));
+    }
+    if !cells.is_empty() {
+        return Err("--cell only works with eval".to_string());
+    }
+    if output.is_some() {
+        return Err("-o only works with recalc".to_string());
+    }
+
+    Ok(Args {
+        command,
+        files,
+        assignments,
+    })
+}
+
+/// Load the table of `path`, apply the assignments and write the results without a terminal
+fn run_batch(args: Args) -> Result<(), Box<dyn Error>> {
+    let path = match &args.command {
+        Command::Eval { path, .. } | Command::Recalc { path, .. } => path,
+        Command::Edit(_) => unreachable!("not a batch command"),
+    };
+    let mut table = args
+        .files
+        .load(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    for (coord, input) in &args.assignments {
+        let operation =
+            parse_input(input).map_err(|e| format!("{}: {}", Address::new(*coord), e))?;
+        table
+            .insert(*coord, operation)
+            .map_err(|e| format!("{}: {}", Address::new(*coord), e))?;
+    }
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    match &args.command {
+        Command::Eval { cells, .. } => {
+            for coord in cells {
+                let value = table.get_value_of_cell(coord);
+                writeln!(
+                    stdout,
+                    "{}",
+                    value.map(|v| v.to_string()).unwrap_or_default()
+                )?;
+            }
+        }
+        Command::Recalc {
+            output: Some(output),
+            ..
+        } => args.files.save(&table, output)?,
+        Command::Recalc { output: None, .. } => {
+            let options = CsvOptions {
+                delimiter: args.files.delimiter.unwrap_or(','),
+                ..CsvOptions::default()
+            };
+            csv::export(&table, &mut stdout, &options)?;
+        }
+        Command::Edit(_) => (),
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let path = match args.command {
+        Command::Edit(path) => path,
+        _ => {
+            if let Err(err) = run_batch(args) {
+                eprintln!("error: {}", err);
+                std::process::exit(1);
+            }
+            return Ok(());
+        }
+    };
+
+    // a file that doesn't exist yet is created by `:w`
+    let table = match &path {
+        Some(path) if path.exists() => args.files.load(path)?,
+        _ => Table::new(),
+    };
+    let mut app = App::new(table, path, args.files);
+
+    // Terminal initialization
+    let stdout = io::stdout().into_raw_mode()?;
+    let stdout = MouseTerminal::from(stdout);
+    let stdout = AlternateScreen::from(stdout);
+    let backend = TermionBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let events = Events::with_config(Config {
+        tick_rate: Duration::from_millis(100),
+    });
+
+    loop {
+        terminal.draw(|f| draw(f, &mut app))?;
 
         match events.next()? {
-            Event::Input(input) => match input {
-                Key::Char('q') => break,
-                Key::Up | Key::Char('w') => selected.up(),
-                Key::Down | Key::Char('s') => selected.down(),
-                Key::Left | Key::Char('a') => selected.left(),
-                Key::Right | Key::Char('d') => selected.right(),
-                _ => (),
-            },
+            Event::Input(key) => {
+                if !app.handle_key(key) {
+                    break;
+                }
+            }
             Event::Tick => (),
         }
     }
 
     Ok(())
 }
-
-// fn main() {
-//     let mut table = Table::new();
-
-//     table.insert(
-//         (1, 1),
-//         table::Operation::None(table::OperationValue::Value(1.0)),
-//     );
-//     table.insert(
-//         (1, 2),
-//         table::Operation::None(table::OperationValue::Value(2.0)),
-//     );
-//     table.insert(
-//         (2, 1),
-//         table::Operation::Add(
-//             table::OperationValue::Cell((1, 1)),
-//             table::OperationValue::Cell((1, 2)),
-//         ),
-//     );
-//     table.insert(
-//         (1, 3),
-//         table::Operation::None(table::OperationValue::Value(std::f64::consts::FRAC_PI_2)),
-//     );
-//     table.insert(
-//         (3, 1),
-//         table::Operation::Sine(table::OperationValue::Cell((1, 4))),
-//     );
-//     println!("-------------------------");
-//     table.insert(
-//         (1, 1),
-//         table::Operation::None(table::OperationValue::Value(-1.0)),
-//     );
-//     table.insert(
-//         (1, 4),
-//         table::Operation::None(table::OperationValue::Value(std::f64::consts::FRAC_PI_4)),
-//     );
-
-//     for (coord, val) in table.iter() {
-//         println!("({}, {}): {}", coord.0, coord.1, val.get_value());
-//     }
-// }
-
-// fn read_input() -> Vec<char> {
-//     let mut input: Vec<char> = Vec::new();
-
-//     // we only care about ASCII for now. Get's replaced later by non-raw input based format anyway
-//     for byte in io::stdin().lock().bytes() {
-//         let curr = byte.unwrap() as char;
-//         if curr == '\n' {
-//             break;
-//         }
-//         input.push(curr);
-//     }
-
-//     input
-// }
-
-// // TODO: add error handling
-// fn parse_input(input: Vec<char>) -> Cell {
-//     let mut x_in: Option<u32> = None;
-//     let mut y_in: Option<u32> = None;
-
-//     let mut buff = String::new();
-//     for c in &input {
-//         if *c == ',' {
-//             if let None = x_in {
-//                 x_in = Some(buff.trim().parse().expect("x not a number"));
-//                 buff = String::new();
-//             }
-//         } else if *c == ':' {
-//             if let None = y_in {
-
-//                 y_in = Some(buff.trim().parse().expect("y not a number"));
-//                 buff = String::new();
-//             }
-//         } else {
-//             buff.push(*c);
-//         }
-//     }
-
-//     let value = buff.trim().parse::<f64>().expect("content must be a float (for now)");
-
-//     let x = match x_in {
-//         Some(n) => n,
-//         None => 0,
-//     };
-//     let y = match y_in {
-//         Some(n) => n,
-//         None => 0,
-//     };
-
-//     Cell { x, y, value, operation: cell::Operation::None }
-// }
@@ -1,31 +1,190 @@
-use std::cell::RefCell;
-use std::rc::{Rc, Weak};
+use std::cmp::Ordering;
+use std::fmt;
 
-type StrongRef = Rc<RefCell<f64>>;
-type WeakRef = Weak<RefCell<f64>>;
+use rayon::prelude::*;
 
-#[derive(Debug)]
+use crate::function::{self, Arity};
+use crate::operation::{self, ById};
+use crate::text;
+
+/// Operation of a cell, referencing other cells by their `CellId`
+pub type Operation = operation::Operation<ById>;
+
+#[derive(Debug, Clone)]
 pub enum CellValue {
     Num(f64),
+    Text(String),
+    Bool(bool),
     /// `Unity` type behaves as expected from mathematics
     /// i.e. for addition `Num` + `Unity` = `Num` (`Unity` behaves like a zero)
     /// for multiplication `Num` * `Unity`= `Num` (`Unity` behaves like a one)
+    /// for exponentiation `Num` ^ `Unity` = `Num` (`Unity` behaves like a one)
+    /// Empty cells have the value `Unity`
     Unity,
+    /// Errors propagate, i.e. any operation with an `Error` operand results in that error
+    Error(CellError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CellError {
+    /// `#REF!`: the referenced cell does not exist (anymore)
+    Ref,
+    /// `#DIV/0!`: division by zero
+    Div0,
+    /// `#VALUE!`: operand has the wrong type
+    Value,
+    /// `#NAME?`: unknown function
+    Name,
+    /// `#NUM!`: argument outside of the domain of a function, or a result which is too large
+    Num,
+    /// `#N/A`: no value available, e.g. no condition of `IFS` is true
+    NA,
+}
+
+impl fmt::Display for CellError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CellError::Ref => write!(f, "#REF!"),
+            CellError::Div0 => write!(f, "#DIV/0!"),
+            CellError::Value => write!(f, "#VALUE!"),
+            CellError::Name => write!(f, "#NAME?"),
+            CellError::Num => write!(f, "#NUM!"),
+            CellError::NA => write!(f, "#N/A"),
+        }
+    }
+}
+
+impl fmt::Display for CellValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CellValue::Num(v) => write!(f, "{}", v),
+            CellValue::Text(s) => write!(f, "{}", s),
+            CellValue::Bool(true) => write!(f, "TRUE"),
+            CellValue::Bool(false) => write!(f, "FALSE"),
+            CellValue::Unity => Ok(()),
+            CellValue::Error(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Number written in `s`, ignoring surrounding whitespace.
+/// Words like `inf` or `NaN` and numbers too large to represent aren't numbers
+pub fn parse_number(s: &str) -> Option<f64> {
+    s.trim().parse::<f64>().ok().filter(|v| v.is_finite())
+}
+
+impl CellValue {
+    /// Coerce into an operand for arithmetic, where `None` stands for `Unity`
+    /// `Bool` counts as 1 or 0 and `Text` needs to be a number, otherwise it's a `#VALUE!`
+    fn to_arithmetic(&self) -> Result<Option<f64>, CellError> {
+        match self {
+            CellValue::Num(v) => Ok(Some(*v)),
+            CellValue::Text(s) => parse_number(s).map(Some).ok_or(CellError::Value),
+            CellValue::Bool(b) => Ok(Some(if *b { 1. } else { 0. })),
+            CellValue::Unity => Ok(None),
+            CellValue::Error(e) => Err(*e),
+        }
+    }
+
+    /// Coerce into a number for functions like `to_arithmetic`, where `Unity` counts as 0
+    pub fn to_number(&self) -> Result<f64, CellError> {
+        self.to_arithmetic().map(|v| v.unwrap_or(0.))
+    }
+
+    /// Coerce into text as displayed, where `Unity` is the empty text
+    pub fn to_text(&self) -> Result<String, CellError> {
+        match self {
+            CellValue::Error(e) => Err(*e),
+            value => Ok(value.to_string()),
+        }
+    }
+
+    /// Text of `self` followed by the text of `other`, see `to_text`.
+    /// `#VALUE!` if it's longer than `text::MAX_LENGTH`
+    pub fn concat(&self, other: &Self) -> Self {
+        match (self.to_text(), other.to_text()) {
+            (Err(e), _) | (_, Err(e)) => CellValue::Error(e),
+            (Ok(s1), Ok(s2)) if s1.len() + s2.len() > text::MAX_LENGTH => {
+                CellValue::Error(CellError::Value)
+            }
+            (Ok(s1), Ok(s2)) => CellValue::Text(s1 + &s2),
+        }
+    }
+
+    /// Coerce into a truth value, where numbers other than 0 are true, `Unity` is false and
+    /// `Text` needs to be `TRUE` or `FALSE` (ignoring case), otherwise it's a `#VALUE!`
+    pub fn to_bool(&self) -> Result<bool, CellError> {
+        match self {
+            CellValue::Num(v) => Ok(*v != 0.),
+            CellValue::Text(s) if s.trim().eq_ignore_ascii_case("true") => Ok(true),
+            CellValue::Text(s) if s.trim().eq_ignore_ascii_case("false") => Ok(false),
+            CellValue::Text(_) => Err(CellError::Value),
+            CellValue::Bool(b) => Ok(*b),
+            CellValue::Unity => Ok(false),
+            CellValue::Error(e) => Err(*e),
+        }
+    }
+
+    /// `self` to the power of `other`
+    pub fn pow(self, other: Self) -> Self {
+        match (self.to_arithmetic(), other.to_arithmetic()) {
+            (Err(e), _) | (_, Err(e)) => CellValue::Error(e),
+            // 0^-n is 1 / 0^n
+            (Ok(Some(v1)), Ok(Some(v2))) if v1 == 0. && v2 < 0. => {
+                CellValue::Error(CellError::Div0)
+            }
+            (Ok(Some(v1)), Ok(Some(v2))) => CellValue::Num(v1.powf(v2)), // both Num
+            (Ok(Some(v1)), Ok(None)) => CellValue::Num(v1),              // one Num, one Unity
+            (Ok(None), Ok(Some(_))) => CellValue::Num(1.),               // one Unity, one Num
+            (Ok(None), Ok(None)) => CellValue::Unity,                    // both Unity
+        }
+    }
+
+    /// Compare two values, where
+    /// - numbers are smaller than text, which is smaller than booleans
+    /// - text is compared case insensitively
+    /// - `Unity` compares like 0, "" or FALSE, depending on the other value
+    /// - comparing an error results in that error
+    pub fn compare(&self, other: &Self) -> Result<Ordering, CellError> {
+        // rank of the type, and the value as one of the types
+        fn key(val: &CellValue, other: &CellValue) -> Result<(u8, CellValue), CellError> {
+            match val {
+                CellValue::Num(_) => Ok((0, val.clone())),
+                CellValue::Text(s) => Ok((1, CellValue::Text(s.to_lowercase()))),
+                CellValue::Bool(_) => Ok((2, val.clone())),
+                CellValue::Unity => match other {
+                    CellValue::Text(_) => Ok((1, CellValue::Text(String::new()))),
+                    CellValue::Bool(_) => Ok((2, CellValue::Bool(false))),
+                    _ => Ok((0, CellValue::Num(0.))),
+                },
+                CellValue::Error(e) => Err(*e),
+            }
+        }
+
+        let (rank1, val1) = key(self, other)?;
+        let (rank2, val2) = key(other, self)?;
+
+        Ok(match (val1, val2) {
+            (CellValue::Num(v1), CellValue::Num(v2)) => {
+                v1.partial_cmp(&v2).unwrap_or(Ordering::Equal)
+            }
+            (CellValue::Text(s1), CellValue::Text(s2)) => s1.cmp(&s2),
+            (CellValue::Bool(b1), CellValue::Bool(b2)) => b1.cmp(&b2),
+            _ => rank1.cmp(&rank2),
+        })
+    }
 }
 
 impl std::ops::Add for CellValue {
     type Output = Self;
 
     fn add(self, other: Self) -> Self {
-        match self {
-            CellValue::Num(v1) => match other {
-                CellValue::Num(v2) => CellValue::Num(v1 + v2), // both Num
-                CellValue::Unity => CellValue::Num(v1),        // one Num, one Unity
-            },
-            CellValue::Unity => match other {
-                CellValue::Num(v2) => CellValue::Num(v2), // one Unity, one Num
-                CellValue::Unity => CellValue::Unity,     // both Unity
-            },
+        match (self.to_arithmetic(), other.to_arithmetic()) {
+            (Err(e), _) | (_, Err(e)) => CellValue::Error(e),
+            (Ok(Some(v1)), Ok(Some(v2))) => CellValue::Num(v1 + v2), // both Num
+            (Ok(Some(v1)), Ok(None)) => CellValue::Num(v1),          // one Num, one Unity
+            (Ok(None), Ok(Some(v2))) => CellValue::Num(v2),          // one Unity, one Num
+            (Ok(None), Ok(None)) => CellValue::Unity,                // both Unity
         }
     }
 }
@@ -34,15 +193,12 @@ impl std::ops::Mul for CellValue {
     type Output = Self;
 
     fn mul(self, other: Self) -> Self {
-        match self {
-            CellValue::Num(v1) => match other {
-                CellValue::Num(v2) => CellValue::Num(v1 * v2), // both Num
-                CellValue::Unity => CellValue::Num(v1),        // one Num, one Unity
-            },
-            CellValue::Unity => match other {
-                CellValue::Num(v2) => CellValue::Num(v2), // one Unity, one Num
-                CellValue::Unity => CellValue::Unity,     // both Unity
-            },
+        match (self.to_arithmetic(), other.to_arithmetic()) {
+            (Err(e), _) | (_, Err(e)) => CellValue::Error(e),
+            (Ok(Some(v1)), Ok(Some(v2))) => CellValue::Num(v1 * v2), // both Num
+            (Ok(Some(v1)), Ok(None)) => CellValue::Num(v1),          // one Num, one Unity
+            (Ok(None), Ok(Some(v2))) => CellValue::Num(v2),          // one Unity, one Num
+            (Ok(None), Ok(None)) => CellValue::Unity,                // both Unity
         }
     }
 }
@@ -51,15 +207,12 @@ impl std::ops::Sub for CellValue {
     type Output = Self;
 
     fn sub(self, other: Self) -> Self {
-        match self {
-            CellValue::Num(v1) => match other {
-                CellValue::Num(v2) => CellValue::Num(v1 - v2), // both Num
-                CellValue::Unity => CellValue::Num(v1),        // one Num, one Unity
-            },
-            CellValue::Unity => match other {
-                CellValue::Num(v2) => CellValue::Num(-v2), // one Unity, one Num
-                CellValue::Unity => CellValue::Unity,      // both Unity
-            },
+        match (self.to_arithmetic(), other.to_arithmetic()) {
+            (Err(e), _) | (_, Err(e)) => CellValue::Error(e),
+            (Ok(Some(v1)), Ok(Some(v2))) => CellValue::Num(v1 - v2), // both Num
+            (Ok(Some(v1)), Ok(None)) => CellValue::Num(v1),          // one Num, one Unity
+            (Ok(None), Ok(Some(v2))) => CellValue::Num(-v2),         // one Unity, one Num
+            (Ok(None), Ok(None)) => CellValue::Unity,                // both Unity
         }
     }
 }
@@ -68,15 +221,51 @@ impl std::ops::Div for CellValue {
     type Output = Self;
 
     fn div(self, other: Self) -> Self {
-        match self {
-            CellValue::Num(v1) => match other {
-                CellValue::Num(v2) => CellValue::Num(v1 / v2), // both Num
-                CellValue::Unity => CellValue::Num(v1),        // one Num, one Unity
-            },
-            CellValue::Unity => match other {
-                CellValue::Num(v2) => CellValue::Num(1. / v2), // one Unity, one Num
-                CellValue::Unity => CellValue::Unity,          // both Unity
-            },
+        match (self.to_arithmetic(), other.to_arithmetic()) {
+            (Err(e), _) | (_, Err(e)) => CellValue::Error(e),
+            (Ok(_), Ok(Some(0.))) => CellValue::Error(CellError::Div0),
+            (Ok(Some(v1)), Ok(Some(v2))) => CellValue::Num(v1 / v2), // both Num
+            (Ok(Some(v1)), Ok(None)) => CellValue::Num(v1),          // one Num, one Unity
+            (Ok(None), Ok(Some(v2))) => CellValue::Num(1. / v2),     // one Unity, one Num
+            (Ok(None), Ok(None)) => CellValue::Unity,                // both Unity
+        }
+    }
+}
+
+impl std::ops::Rem for CellValue {
+    type Output = Self;
+
+    /// Remainder of the floored division, i.e. the result has the sign of `other`
+    fn rem(self, other: Self) -> Self {
+        match (self.to_arithmetic(), other.to_arithmetic()) {
+            (Err(e), _) | (_, Err(e)) => CellValue::Error(e),
+            (Ok(_), Ok(Some(0.))) => CellValue::Error(CellError::Div0),
+            (Ok(Some(v1)), Ok(Some(v2))) => CellValue::Num(floored_rem(v1, v2)), // both Num
+            (Ok(Some(v1)), Ok(None)) => CellValue::Num(v1), // one Num, one Unity
+            (Ok(None), Ok(Some(v2))) => CellValue::Num(floored_rem(1., v2)), // one Unity, one Num
+            (Ok(None), Ok(None)) => CellValue::Unity,       // both Unity
+        }
+    }
+}
+
+/// `v1 % v2`, but with the sign of `v2` like the modulo in spreadsheets
+fn floored_rem(v1: f64, v2: f64) -> f64 {
+    let r = v1 % v2;
+    if r != 0. && (r < 0.) != (v2 < 0.) {
+        r + v2
+    } else {
+        r
+    }
+}
+
+impl std::ops::Neg for CellValue {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        match self.to_arithmetic() {
+            Ok(Some(v)) => CellValue::Num(-v),
+            Ok(None) => CellValue::Unity,
+            Err(e) => CellValue::Error(e),
         }
     }
 }
@@ -85,19 +274,17 @@ impl PartialEq for CellValue {
     // we want Num(v1) == Num(v1)
     // Num(_) != Unity
     // Unity == Unity
+    // Error(e1) == Error(e2) if e1 == e2
+    // and likewise for Text and Bool. Values of different types are never equal
     fn eq(&self, other: &Self) -> bool {
-        if let CellValue::Num(v1) = *self {
-            if let CellValue::Num(v2) = *other {
-                return v1 == v2;
-            }
+        match (self, other) {
+            (CellValue::Num(v1), CellValue::Num(v2)) => v1 == v2,
+            (CellValue::Text(s1), CellValue::Text(s2)) => s1 == s2,
+            (CellValue::Bool(b1), CellValue::Bool(b2)) => b1 == b2,
+            (CellValue::Unity, CellValue::Unity) => true,
+            (CellValue::Error(e1), CellValue::Error(e2)) => e1 == e2,
+            _ => false,
         }
-        if let CellValue::Unity = *self {
-            if let CellValue::Unity = *other {
-                return true;
-            }
-        }
-
-        false
     }
 }
 
@@ -142,6 +329,133 @@ mod tests {
         assert_eq!(CellValue::Unity / CellValue::Unity, CellValue::Unity);
     }
 
+    #[test]
+    fn pow_rem_neg_cellvalue() {
+        assert_eq!(
+            CellValue::Num(2.).pow(CellValue::Num(3.)),
+            CellValue::Num(8.)
+        );
+        assert_eq!(CellValue::Num(2.).pow(CellValue::Unity), CellValue::Num(2.));
+        assert_eq!(CellValue::Unity.pow(CellValue::Num(3.)), CellValue::Num(1.));
+        assert_eq!(CellValue::Unity.pow(CellValue::Unity), CellValue::Unity);
+
+        assert_eq!(CellValue::Num(7.) % CellValue::Num(3.), CellValue::Num(1.));
+        assert_eq!(CellValue::Num(-7.) % CellValue::Num(3.), CellValue::Num(2.));
+        assert_eq!(
+            CellValue::Num(7.) % CellValue::Num(-3.),
+            CellValue::Num(-2.)
+        );
+        assert_eq!(CellValue::Num(7.) % CellValue::Unity, CellValue::Num(7.));
+        assert_eq!(CellValue::Unity % CellValue::Unity, CellValue::Unity);
+
+        assert_eq!(-CellValue::Num(2.), CellValue::Num(-2.));
+        assert_eq!(-CellValue::Unity, CellValue::Unity);
+    }
+
+    #[test]
+    fn ops_cellvalue_error() {
+        let ref_err = || CellValue::Error(CellError::Ref);
+
+        assert_eq!(ref_err() + CellValue::Num(1.), ref_err());
+        assert_eq!(CellValue::Num(1.) - ref_err(), ref_err());
+        assert_eq!(CellValue::Unity * ref_err(), ref_err());
+        assert_eq!(ref_err() / CellValue::Unity, ref_err());
+        assert_eq!(ref_err().pow(CellValue::Num(2.)), ref_err());
+        assert_eq!(CellValue::Num(1.) % ref_err(), ref_err());
+        assert_eq!(-ref_err(), ref_err());
+        // the left error wins
+        assert_eq!(ref_err() + CellValue::Error(CellError::Name), ref_err());
+    }
+
+    #[test]
+    fn div_by_zero_cellvalue() {
+        let div0 = CellValue::Error(CellError::Div0);
+
+        assert_eq!(CellValue::Num(1.) / CellValue::Num(0.), div0);
+        assert_eq!(CellValue::Unity / CellValue::Num(0.), div0);
+        assert_eq!(CellValue::Num(1.) % CellValue::Num(0.), div0);
+        assert_eq!(CellValue::Num(0.).pow(CellValue::Num(-1.)), div0);
+        assert_eq!(
+            CellValue::Num(0.).pow(CellValue::Num(0.)),
+            CellValue::Num(1.)
+        );
+    }
+
+    #[test]
+    fn ops_cellvalue_coercion() {
+        let text = |s: &str| CellValue::Text(s.to_string());
+
+        assert_eq!(text("2") + CellValue::Num(3.), CellValue::Num(5.));
+        assert_eq!(text(" 2.5 ") * text("2"), CellValue::Num(5.));
+        assert_eq!(
+            text("abc") + CellValue::Num(3.),
+            CellValue::Error(CellError::Value)
+        );
+        assert_eq!(
+            CellValue::Bool(true) + CellValue::Bool(true),
+            CellValue::Num(2.)
+        );
+        assert_eq!(
+            CellValue::Num(3.) * CellValue::Bool(false),
+            CellValue::Num(0.)
+        );
+        assert_eq!(-CellValue::Bool(true), CellValue::Num(-1.));
+        assert_eq!(
+            text("x") - CellValue::Unity,
+            CellValue::Error(CellError::Value)
+        );
+        for word in &["nan", "Infinity", "-inf", "1e999"] {
+            assert_eq!(
+                text(word) + CellValue::Num(1.),
+                CellValue::Error(CellError::Value)
+            );
+            assert_eq!(text(word).to_number(), Err(CellError::Value));
+        }
+    }
+
+    #[test]
+    fn compare_cellvalue() {
+        let text = |s: &str| CellValue::Text(s.to_string());
+
+        assert_eq!(
+            CellValue::Num(1.).compare(&CellValue::Num(2.)),
+            Ok(Ordering::Less)
+        );
+        assert_eq!(text("abc").compare(&text("ABC")), Ok(Ordering::Equal));
+        assert_eq!(text("b").compare(&text("a")), Ok(Ordering::Greater));
+        assert_eq!(CellValue::Num(1e9).compare(&text("0")), Ok(Ordering::Less));
+        assert_eq!(
+            text("z").compare(&CellValue::Bool(false)),
+            Ok(Ordering::Less)
+        );
+        assert_eq!(
+            CellValue::Bool(true).compare(&CellValue::Bool(false)),
+            Ok(Ordering::Greater)
+        );
+        assert_eq!(
+            CellValue::Unity.compare(&CellValue::Num(0.)),
+            Ok(Ordering::Equal)
+        );
+        assert_eq!(CellValue::Unity.compare(&text("")), Ok(Ordering::Equal));
+        assert_eq!(
+            CellValue::Bool(false).compare(&CellValue::Unity),
+            Ok(Ordering::Equal)
+        );
+        assert_eq!(
+            CellValue::Num(1.).compare(&CellValue::Error(CellError::Ref)),
+            Err(CellError::Ref)
+        );
+    }
+
+    #[test]
+    fn display_cellvalue() {
+        assert_eq!(CellValue::Num(1.5).to_string(), "1.5");
+        assert_eq!(CellValue::Text("abc".to_string()).to_string(), "abc");
+        assert_eq!(CellValue::Bool(true).to_string(), "TRUE");
+        assert_eq!(CellValue::Unity.to_string(), "");
+        assert_eq!(CellValue::Error(CellError::Div0).to_string(), "#DIV/0!");
+    }
+
     #[test]
     fn cmp_cellvalue() {
         assert!(CellValue::Num(2.) == CellValue::Num(2.));
@@ -149,78 +463,798 @@ mod tests {
         assert!(CellValue::Unity != CellValue::Num(2.));
         assert!(CellValue::Num(2.) != CellValue::Unity);
         assert!(CellValue::Unity == CellValue::Unity);
+        assert!(CellValue::Error(CellError::Ref) == CellValue::Error(CellError::Ref));
+        assert!(CellValue::Error(CellError::Ref) != CellValue::Error(CellError::Div0));
+        assert!(CellValue::Error(CellError::Ref) != CellValue::Unity);
+        assert!(CellValue::Text("a".to_string()) == CellValue::Text("a".to_string()));
+        assert!(CellValue::Text("1".to_string()) != CellValue::Num(1.));
+        assert!(CellValue::Bool(true) != CellValue::Num(1.));
+    }
+
+    #[test]
+    fn arena_ids() {
+        let mut cells = Cells::new();
+
+        let mut cell = Cell::new();
+        cell.set_value(1.);
+        let a = cells.insert(cell);
+        let b = cells.insert(Cell::new());
+        assert_eq!(cells.value(a), CellValue::Num(1.));
+        assert_eq!(cells.value(b), CellValue::Unity);
+
+        assert!(cells.remove(a).is_some());
+        assert!(cells.remove(a).is_none());
+        assert_eq!(cells.value(a), CellValue::Error(CellError::Ref));
+
+        // the slot is reused, but the old id stays invalid
+        let c = cells.insert(Cell::new());
+        assert_ne!(a, c);
+        assert_eq!(cells.value(a), CellValue::Error(CellError::Ref));
+        assert_eq!(cells.value(c), CellValue::Unity);
+
+        cells.get_mut(b).unwrap().set_operation(Operation::Add(
+            Box::new(Operation::Cell(c)),
+            Box::new(Operation::Cell(a)),
+        ));
+        cells.update(b);
+        assert_eq!(cells.value(b), CellValue::Error(CellError::Ref));
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Cell {
-    value: StrongRef,
-    operation: Operation,
+    value: CellValue,
+    /// `None` for empty cells
+    operation: Option<Operation>,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Cell {
+    /// Create an empty cell, i.e. with value `CellValue::Unity`
     pub fn new() -> Cell {
         Cell {
-            value: Rc::new(RefCell::new(0.0)),
-            operation: Operation::None(OperationValue::Value(0.0)),
+            value: CellValue::Unity,
+            operation: None,
         }
     }
 
     /// Sets value directly to `val`
-    /// since setting value directly would violate any Operation other than None,
-    /// set `operation` to `Operation::None`
+    /// since setting value directly would violate any other Operation,
+    /// set `operation` to `Operation::Value`
     pub fn set_value(&mut self, val: f64) {
-        // *self.value.borrow_mut() = val;
-        self.operation = Operation::None(OperationValue::Value(val));
-        self.update();
+        self.operation = Some(Operation::Value(val));
+        self.value = CellValue::Num(val);
     }
 
     /// Sets `operation`
     /// `op` needs to be initialized with references already
     pub fn set_operation(&mut self, op: Operation) {
-        self.operation = op;
+        self.operation = Some(op);
+    }
+
+    /// Drop the operation, so that the cell evaluates to `CellValue::Unity`
+    pub fn clear_operation(&mut self) {
+        self.operation = None;
     }
 
-    /// Get `value` as `f64`
-    pub fn get_value(&self) -> f64 {
-        *self.value.borrow()
+    /// Whether the cell has no content of its own
+    pub fn is_empty(&self) -> bool {
+        self.operation.is_none()
     }
 
-    /// Return a weak pointer to `value` to initialize Operation
-    pub fn get_ptr_to_value(&self) -> WeakRef {
-        Rc::downgrade(&self.value)
+    /// Get `value`. Empty cells have value `CellValue::Unity`
+    pub fn get_value(&self) -> CellValue {
+        self.value.clone()
     }
+}
+
+/// Id of a cell in `Cells`. The id of a removed cell stays invalid, even if its slot is reused
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CellId {
+    index: u32,
+    generation: u32,
+}
+
+/// Rectangular range of cells, see `operation::ById`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CellRange {
+    /// every cell, row by row
+    pub ids: Vec<CellId>,
+    /// number of columns
+    pub width: usize,
+}
+
+impl CellRange {
+    pub fn height(&self) -> usize {
+        self.ids.len().checked_div(self.width).unwrap_or(0)
+    }
+
+    /// Cell at the 0-based `row` and `column`
+    pub fn get(&self, row: usize, column: usize) -> CellId {
+        self.ids[row * self.width + column]
+    }
+
+    /// Whether the range is a single row or column
+    pub fn is_vector(&self) -> bool {
+        self.width == 1 || self.height() == 1
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    /// incremented whenever the cell in this slot is removed
+    generation: u32,
+    cell: Option<Cell>,
+}
+
+/// Smallest number of cells `Cells::update_independent` evaluates in parallel.
+/// Evaluating a cell is cheap, so smaller batches aren't worth distributing over threads
+const PARALLEL_BATCH_SIZE: usize = 256;
+
+/// Arena holding all cells of a table. Operations reference other cells by their `CellId`
+#[derive(Debug, Clone, Default)]
+pub struct Cells {
+    slots: Vec<Slot>,
+    /// indices of the slots without cell
+    free: Vec<u32>,
+}
+
+impl Cells {
+    pub fn new() -> Cells {
+        Cells {
+            slots: vec![],
+            free: vec![],
+        }
+    }
+
+    pub fn insert(&mut self, cell: Cell) -> CellId {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.cell = Some(cell);
+                CellId {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    cell: Some(cell),
+                });
+                CellId {
+                    index: self.slots.len() as u32 - 1,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    pub fn remove(&mut self, id: CellId) -> Option<Cell> {
+        let slot = self.slots.get_mut(id.index as usize)?;
+        if slot.generation != id.generation {
+            return None;
+        }
+
+        let cell = slot.cell.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(id.index);
+        Some(cell)
+    }
+
+    pub fn get(&self, id: CellId) -> Option<&Cell> {
+        self.slots
+            .get(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.cell.as_ref())
+    }
+
+    pub fn get_mut(&mut self, id: CellId) -> Option<&mut Cell> {
+        self.slots
+            .get_mut(id.index as usize)
+            .filter(|slot| slot.generation == id.generation)
+            .and_then(|slot| slot.cell.as_mut())
+    }
+
+    /// Value of the cell `id`, or `#REF!` if the cell does not exist anymore
+    pub fn value(&self, id: CellId) -> CellValue {
+        match self.get(id) {
+            Some(cell) => cell.value.clone(),
+            None => CellValue::Error(CellError::Ref),
+        }
+    }
+
+    /// Value of the operation of the cell `id` with the current values of the cells it references.
+    /// `None` if the cell does not exist
+    pub fn evaluate(&self, id: CellId) -> Option<CellValue> {
+        let cell = self.get(id)?;
+        Some(match &cell.operation {
+            Some(op) => op.evaluate(self),
+            None => CellValue::Unity,
+        })
+    }
+
+    /// Evaluate the cell `id` and store its new value, see `evaluate`.
+    /// Returns whether the value changed
+    pub fn update(&mut self, id: CellId) -> bool {
+        match self.evaluate(id) {
+            Some(value) => self.store(id, value),
+            None => false,
+        }
+    }
+
+    /// `update` all cells `ids`, which must not reference each other, and call `changed` with the
+    /// index in `ids` of every cell whose value changed. Large batches are evaluated in parallel,
+    /// with the same result
+    pub fn update_independent<F: FnMut(usize)>(&mut self, ids: &[CellId], mut changed: F) {
+        if ids.len() < PARALLEL_BATCH_SIZE {
+            for (i, id) in ids.iter().enumerate() {
+                if self.update(*id) {
+                    changed(i);
+                }
+            }
+            return;
+        }
+
+        let values: Vec<Option<CellValue>> = ids.par_iter().map(|id| self.evaluate(*id)).collect();
+        for (i, (id, value)) in ids.iter().zip(values).enumerate() {
+            if let Some(value) = value {
+                if self.store(*id, value) {
+                    changed(i);
+                }
+            }
+        }
+    }
+
+    /// Set the value of the existing cell `id`. Returns whether it changed
+    fn store(&mut self, id: CellId, value: CellValue) -> bool {
+        let cell = self.get_mut(id).unwrap();
+        let changed = cell.value != value;
+        cell.value = value;
+        changed
+    }
+}
+
+/// Functions over any number of values and ranges
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Sum,
+    Average,
+    Min,
+    Max,
+    /// number of numeric values, ignoring errors
+    Count,
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Aggregate::Sum => write!(f, "SUM"),
+            Aggregate::Average => write!(f, "AVERAGE"),
+            Aggregate::Min => write!(f, "MIN"),
+            Aggregate::Max => write!(f, "MAX"),
+            Aggregate::Count => write!(f, "COUNT"),
+        }
+    }
+}
+
+impl Aggregate {
+    /// Apply the aggregate to `args`
+    /// Within ranges only numbers count, text and booleans are ignored.
+    /// Arguments given directly are coerced like for arithmetic.
+    fn evaluate(&self, args: &[Operation], cells: &Cells) -> CellValue {
+        if args.is_empty() {
+            return CellValue::Error(CellError::Value);
+        }
+
+        let mut numbers = vec![];
+        let mut error = None;
+
+        for arg in args {
+            match arg {
+                Operation::Range(range) => {
+                    for id in &range.ids {
+                        match cells.value(*id) {
+                            CellValue::Num(v) => numbers.push(v),
+                            CellValue::Error(e) => {
+                                error.get_or_insert(e);
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                _ => match arg.evaluate(cells).to_arithmetic() {
+                    Ok(Some(v)) => numbers.push(v),
+                    Ok(None) => (),
+                    Err(e) => {
+                        error.get_or_insert(e);
+                    }
+                },
+            }
+        }
+
+        if let (Some(e), false) = (error, *self == Aggregate::Count) {
+            return CellValue::Error(e);
+        }
+
+        match self {
+            Aggregate::Sum => CellValue::Num(numbers.iter().sum()),
+            Aggregate::Average if numbers.is_empty() => CellValue::Error(CellError::Div0),
+            Aggregate::Average => {
+                CellValue::Num(numbers.iter().sum::<f64>() / numbers.len() as f64)
+            }
+            Aggregate::Min => {
+                CellValue::Num(numbers.iter().copied().reduce(f64::min).unwrap_or(0.))
+            }
+            Aggregate::Max => {
+                CellValue::Num(numbers.iter().copied().reduce(f64::max).unwrap_or(0.))
+            }
+            Aggregate::Count => CellValue::Num(numbers.len() as f64),
+        }
+    }
+}
+
+/// Comparison operators, which result in a `Bool`. See `CellValue::compare` for the order
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Comparison::Equal => write!(f, "="),
+            Comparison::NotEqual => write!(f, "<>"),
+            Comparison::Less => write!(f, "<"),
+            Comparison::LessEqual => write!(f, "<="),
+            Comparison::Greater => write!(f, ">"),
+            Comparison::GreaterEqual => write!(f, ">="),
+        }
+    }
+}
 
-    pub fn update(&self) {
-        *self.value.borrow_mut() = match &self.operation {
-            Operation::None(o) => self.match_operation_value(o),
-            Operation::Add(o1, o2) => {
-                let n1 = self.match_operation_value(o1);
-                let n2 = self.match_operation_value(o2);
+impl Comparison {
+    pub fn apply(&self, v1: &CellValue, v2: &CellValue) -> CellValue {
+        let ordering = match v1.compare(v2) {
+            Ok(ordering) => ordering,
+            Err(e) => return CellValue::Error(e),
+        };
+        CellValue::Bool(match self {
+            Comparison::Equal => ordering == Ordering::Equal,
+            Comparison::NotEqual => ordering != Ordering::Equal,
+            Comparison::Less => ordering == Ordering::Less,
+            Comparison::LessEqual => ordering != Ordering::Greater,
+            Comparison::Greater => ordering == Ordering::Greater,
+            Comparison::GreaterEqual => ordering != Ordering::Less,
+        })
+    }
+}
 
-                n1 + n2
+/// Logical and conditional functions. Unlike other functions they only evaluate the
+/// arguments they need, e.g. `IF` evaluates either the second or the third argument
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Logical {
+    /// `IF(condition, then, [else])`, where a missing `else` is `FALSE`
+    If,
+    /// `AND(a, b, ...)`, ranges contribute their numbers and booleans
+    And,
+    /// `OR(a, b, ...)`, like `AND`
+    Or,
+    /// `XOR(a, b, ...)`: whether an odd number of arguments is true, like `AND`
+    Xor,
+    /// `NOT(a)`
+    Not,
+    /// `IFERROR(value, value_if_error)`
+    IfError,
+    /// `IFS(condition1, value1, condition2, value2, ...)`: the value of the first true condition,
+    /// `#N/A` if there is none
+    Ifs,
+    /// `SWITCH(value, case1, result1, case2, result2, ..., [default])`: the result of the first
+    /// case equal to value, otherwise `default` or `#N/A`
+    Switch,
+}
+
+impl fmt::Display for Logical {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Logical::If => write!(f, "IF"),
+            Logical::And => write!(f, "AND"),
+            Logical::Or => write!(f, "OR"),
+            Logical::Xor => write!(f, "XOR"),
+            Logical::Not => write!(f, "NOT"),
+            Logical::IfError => write!(f, "IFERROR"),
+            Logical::Ifs => write!(f, "IFS"),
+            Logical::Switch => write!(f, "SWITCH"),
+        }
+    }
+}
+
+impl Logical {
+    fn arity(&self) -> Arity {
+        match self {
+            Logical::If => Arity::between(2, 3),
+            Logical::And | Logical::Or | Logical::Xor => Arity { min: 1, max: None },
+            Logical::Not => Arity::exactly(1),
+            Logical::IfError => Arity::exactly(2),
+            Logical::Ifs => Arity { min: 2, max: None },
+            Logical::Switch => Arity { min: 3, max: None },
+        }
+    }
+
+    /// Apply the function to `args`, evaluating them from left to right only as far as necessary.
+    /// Wrong numbers of arguments result in `#VALUE!`
+    fn evaluate(&self, args: &[Operation], cells: &Cells) -> CellValue {
+        if !self.arity().accepts(args.len()) || (*self == Logical::Ifs && args.len() % 2 != 0) {
+            return CellValue::Error(CellError::Value);
+        }
+        let condition = |i: usize| args[i].evaluate(cells).to_bool();
+
+        let result = match self {
+            Logical::If => condition(0).map(|c| match (c, args.get(2)) {
+                (true, _) => args[1].evaluate(cells),
+                (false, Some(otherwise)) => otherwise.evaluate(cells),
+                (false, None) => CellValue::Bool(false),
+            }),
+            Logical::And => Logical::truth_values(args, cells, Some(false))
+                .map(|values| CellValue::Bool(values.iter().all(|v| *v))),
+            Logical::Or => Logical::truth_values(args, cells, Some(true))
+                .map(|values| CellValue::Bool(values.iter().any(|v| *v))),
+            Logical::Xor => Logical::truth_values(args, cells, None)
+                .map(|values| CellValue::Bool(values.iter().filter(|v| **v).count() % 2 == 1)),
+            Logical::Not => condition(0).map(|c| CellValue::Bool(!c)),
+            Logical::IfError => match args[0].evaluate(cells) {
+                CellValue::Error(_) => Ok(args[1].evaluate(cells)),
+                value => Ok(value),
+            },
+            Logical::Ifs => (0..args.len())
+                .step_by(2)
+                .find_map(|i| match condition(i) {
+                    Ok(true) => Some(Ok(args[i + 1].evaluate(cells))),
+                    Ok(false) => None,
+                    Err(e) => Some(Err(e)),
+                })
+                .unwrap_or(Err(CellError::NA)),
+            Logical::Switch => {
+                let value = args[0].evaluate(cells);
+                args[1..]
+                    .chunks(2)
+                    .find_map(|case| match case {
+                        [candidate, result] => {
+                            match Comparison::Equal.apply(&value, &candidate.evaluate(cells)) {
+                                CellValue::Bool(true) => Some(Ok(result.evaluate(cells))),
+                                CellValue::Error(e) => Some(Err(e)),
+                                _ => None,
+                            }
+                        }
+                        [default] => Some(Ok(default.evaluate(cells))),
+                        _ => None,
+                    })
+                    .unwrap_or(Err(CellError::NA))
             }
-            Operation::Sine(o) => self.match_operation_value(o).sin(),
+        };
+
+        result.unwrap_or_else(CellValue::Error)
+    }
+
+    /// Truth values of `args` for `AND`, `OR` and `XOR`, from left to right up to and including
+    /// the first one equal to `stop_at`. Within ranges text and empty cells are ignored,
+    /// but at least one value is needed
+    fn truth_values(
+        args: &[Operation],
+        cells: &Cells,
+        stop_at: Option<bool>,
+    ) -> Result<Vec<bool>, CellError> {
+        let mut values = Vec::new();
+        for arg in args {
+            let operands = match arg {
+                Operation::Range(range) => range
+                    .ids
+                    .iter()
+                    .map(|id| cells.value(*id))
+                    .filter(|v| !matches!(v, CellValue::Text(_) | CellValue::Unity))
+                    .collect(),
+                _ => vec![arg.evaluate(cells)],
+            };
+            for operand in operands {
+                let value = operand.to_bool()?;
+                values.push(value);
+                if Some(value) == stop_at {
+                    return Ok(values);
+                }
+            }
+        }
+
+        if values.is_empty() {
+            return Err(CellError::Value);
+        }
+        Ok(values)
+    }
+}
+
+/// Functions looking up values in ranges. Lookup values which aren't found result in `#N/A`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Lookup {
+    /// `VLOOKUP(value, range, column, [approximate])`: the cell in `column` of the first row of
+    /// `range` whose first cell equals `value`. With `approximate`, which is the default, the
+    /// first column must be sorted ascending and the last row not greater than `value` is taken
+    VLookup,
+    /// `HLOOKUP(value, range, row, [approximate])`: like `VLOOKUP` with rows and columns swapped
+    HLookup,
+    /// `INDEX(range, row, [column])`: the cell at `row` and `column`, which defaults to 1.
+    /// For a single row the only index counts the columns
+    Index,
+    /// `MATCH(value, range, [type])`: position of `value` in a single row or column. `type` 1,
+    /// the default, expects ascending order and finds the last cell not greater than `value`,
+    /// -1 expects descending order and finds the last cell not less, 0 finds an equal cell
+    Match,
+    /// `XLOOKUP(value, lookup, result, [if_not_found], [mode], [search])`: the cell of `result`
+    /// at the position of `value` in `lookup`, which are single rows or columns of equal length.
+    /// `mode` 0 finds an equal cell, -1 and 1 otherwise the next smaller or larger one and
+    /// 2 matches wildcards. `search` -1 searches from the end instead
+    XLookup,
+}
+
+impl fmt::Display for Lookup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Lookup::VLookup => write!(f, "VLOOKUP"),
+            Lookup::HLookup => write!(f, "HLOOKUP"),
+            Lookup::Index => write!(f, "INDEX"),
+            Lookup::Match => write!(f, "MATCH"),
+            Lookup::XLookup => write!(f, "XLOOKUP"),
         }
     }
+}
+
+/// How a lookup value is searched for, see `Lookup`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Matching {
+    /// an equal value, or with `wildcards` text matching the lookup value as pattern,
+    /// see `text::matches_pattern`
+    Exact { wildcards: bool },
+    /// the last value before the first one past the lookup value, in ascending order or descending
+    Sorted { descending: bool },
+    /// an equal value, otherwise the closest smaller or larger one
+    Nearest { larger: bool },
+}
+
+impl Matching {
+    /// Position of `value` in `candidates`. Only candidates of the same type as `value` match,
+    /// e.g. text never matches a number. `reverse` searches from the end, except for `Sorted`
+    fn find(&self, value: &CellValue, candidates: &[CellValue], reverse: bool) -> Option<usize> {
+        let same_type = |c: &CellValue| std::mem::discriminant(c) == std::mem::discriminant(value);
+        let order = |c: &CellValue| Some(c).filter(|c| same_type(c))?.compare(value).ok();
+        let mut positions: Box<dyn Iterator<Item = usize>> = if reverse {
+            Box::new((0..candidates.len()).rev())
+        } else {
+            Box::new(0..candidates.len())
+        };
 
-    fn match_operation_value(&self, op: &OperationValue) -> f64 {
-        match op {
-            OperationValue::Value(val) => *val,
-            OperationValue::Cell(c) => *c.upgrade().unwrap().borrow(),
+        match *self {
+            Matching::Exact { wildcards } => match value {
+                CellValue::Text(pattern) if wildcards && pattern.contains(['*', '?', '~']) => {
+                    positions.find(|i| match &candidates[*i] {
+                        CellValue::Text(t) => text::matches_pattern(pattern, t),
+                        _ => false,
+                    })
+                }
+                _ => positions.find(|i| order(&candidates[*i]) == Some(Ordering::Equal)),
+            },
+            Matching::Sorted { descending } => {
+                let past = if descending {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                };
+                let mut found = None;
+                for (i, candidate) in candidates.iter().enumerate() {
+                    match order(candidate) {
+                        Some(o) if o == past => break,
+                        Some(_) => found = Some(i),
+                        None => (),
+                    }
+                }
+                found
+            }
+            Matching::Nearest { larger } => {
+                let side = if larger {
+                    Ordering::Greater
+                } else {
+                    Ordering::Less
+                };
+                let mut nearest: Option<usize> = None;
+                for i in positions {
+                    match order(&candidates[i]) {
+                        Some(Ordering::Equal) => return Some(i),
+                        // closer than the nearest so far if it's on the other side of it
+                        Some(o) if o == side => {
+                            let closer = nearest.map_or(true, |n| {
+                                candidates[n].compare(&candidates[i]) == Ok(side)
+                            });
+                            if closer {
+                                nearest = Some(i);
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                nearest
+            }
         }
     }
 }
 
-#[derive(Debug)]
-pub enum Operation {
-    None(OperationValue),
-    Add(OperationValue, OperationValue),
-    Sine(OperationValue),
+impl Lookup {
+    fn arity(&self) -> Arity {
+        match self {
+            Lookup::VLookup | Lookup::HLookup => Arity::between(3, 4),
+            Lookup::Index | Lookup::Match => Arity::between(2, 3),
+            Lookup::XLookup => Arity::between(3, 6),
+        }
+    }
+
+    /// Apply the function to `args`. Ranges have to be given as such, e.g. `A1:B5`, otherwise
+    /// and for wrong numbers of arguments the result is `#VALUE!`.
+    /// Indices outside of the range result in `#REF!`
+    fn evaluate(&self, args: &[Operation], cells: &Cells) -> CellValue {
+        if !self.arity().accepts(args.len()) {
+            return CellValue::Error(CellError::Value);
+        }
+        self.lookup(args, cells).unwrap_or_else(CellValue::Error)
+    }
+
+    fn lookup(&self, args: &[Operation], cells: &Cells) -> Result<CellValue, CellError> {
+        let range = |i: usize| match &args[i] {
+            Operation::Range(range) => Ok(range),
+            _ => Err(CellError::Value),
+        };
+        // optional integer argument `i`, which has to be finite
+        let integer = |i: usize, default: f64| match args.get(i) {
+            Some(arg) => match arg.evaluate(cells).to_number()? {
+                n if n.is_finite() => Ok(n.trunc()),
+                _ => Err(CellError::Value),
+            },
+            None => Ok(default),
+        };
+        let values = |ids: &mut dyn Iterator<Item = CellId>| -> Vec<CellValue> {
+            ids.map(|id| cells.value(id)).collect()
+        };
+        let value = match args[0].evaluate(cells) {
+            CellValue::Error(e) if *self != Lookup::Index => return Err(e),
+            value => value,
+        };
+
+        match self {
+            Lookup::VLookup | Lookup::HLookup => {
+                let range = range(1)?;
+                let index = integer(2, 1.)?;
+                let matching = match args.get(3) {
+                    Some(arg) if !arg.evaluate(cells).to_bool()? => {
+                        Matching::Exact { wildcards: true }
+                    }
+                    _ => Matching::Sorted { descending: false },
+                };
+                let (count, size) = match self {
+                    Lookup::VLookup => (range.height(), range.width),
+                    _ => (range.width, range.height()),
+                };
+                if index < 1. {
+                    return Err(CellError::Value);
+                }
+                if index > size as f64 {
+                    return Err(CellError::Ref);
+                }
+
+                let at = |position: usize, index: usize| match self {
+                    Lookup::VLookup => range.get(position, index),
+                    _ => range.get(index, position),
+                };
+                let keys = values(&mut (0..count).map(|p| at(p, 0)));
+                let position = matching.find(&value, &keys, false).ok_or(CellError::NA)?;
+                Ok(cells.value(at(position, index as usize - 1)))
+            }
+            Lookup::Index => {
+                let range = range(0)?;
+                let (mut row, mut column) = (integer(1, 1.)?, integer(2, 1.)?);
+                if args.len() == 2 && range.height() == 1 {
+                    (row, column) = (1., row);
+                }
+                if row < 1. || column < 1. {
+                    return Err(CellError::Value);
+                }
+                if row > range.height() as f64 || column > range.width as f64 {
+                    return Err(CellError::Ref);
+                }
+                Ok(cells.value(range.get(row as usize - 1, column as usize - 1)))
+            }
+            Lookup::Match => {
+                let range = range(1)?;
+                let matching = match integer(2, 1.)? {
+                    t if t > 0. => Matching::Sorted { descending: false },
+                    t if t < 0. => Matching::Sorted { descending: true },
+                    _ => Matching::Exact { wildcards: true },
+                };
+                if !range.is_vector() {
+                    return Err(CellError::NA);
+                }
+
+                let candidates = values(&mut range.ids.iter().copied());
+                let position = matching
+                    .find(&value, &candidates, false)
+                    .ok_or(CellError::NA)?;
+                Ok(CellValue::Num((position + 1) as f64))
+            }
+            Lookup::XLookup => {
+                let (lookup, result) = (range(1)?, range(2)?);
+                let matching = match integer(4, 0.)? as i64 {
+                    0 => Matching::Exact { wildcards: false },
+                    -1 => Matching::Nearest { larger: false },
+                    1 => Matching::Nearest { larger: true },
+                    2 => Matching::Exact { wildcards: true },
+                    _ => return Err(CellError::Value),
+                };
+                let reverse = match integer(5, 1.)? as i64 {
+                    1 => false,
+                    -1 => true,
+                    _ => return Err(CellError::Value),
+                };
+                if !lookup.is_vector()
+                    || !result.is_vector()
+                    || lookup.ids.len() != result.ids.len()
+                {
+                    return Err(CellError::Value);
+                }
+
+                let candidates = values(&mut lookup.ids.iter().copied());
+                match matching.find(&value, &candidates, reverse) {
+                    Some(position) => Ok(cells.value(result.ids[position])),
+                    None => match args.get(3) {
+                        Some(if_not_found) => Ok(if_not_found.evaluate(cells)),
+                        None => Err(CellError::NA),
+                    },
+                }
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
-pub enum OperationValue {
-    Value(f64),
-    Cell(WeakRef),
+impl Operation {
+    /// Value of the operation, reading referenced cells from `cells`
+    fn evaluate(&self, cells: &Cells) -> CellValue {
+        match self {
+            Operation::Value(v) => CellValue::Num(*v),
+            Operation::Text(s) => CellValue::Text(s.clone()),
+            Operation::Bool(b) => CellValue::Bool(*b),
+            Operation::Error(e) => CellValue::Error(*e),
+            Operation::Cell(id) => cells.value(*id),
+            Operation::Add(o1, o2) => o1.evaluate(cells) + o2.evaluate(cells),
+            Operation::Sub(o1, o2) => o1.evaluate(cells) - o2.evaluate(cells),
+            Operation::Mul(o1, o2) => o1.evaluate(cells) * o2.evaluate(cells),
+            Operation::Div(o1, o2) => o1.evaluate(cells) / o2.evaluate(cells),
+            Operation::Pow(o1, o2) => o1.evaluate(cells).pow(o2.evaluate(cells)),
+            Operation::Mod(o1, o2) => o1.evaluate(cells) % o2.evaluate(cells),
+            Operation::Neg(o) => -o.evaluate(cells),
+            Operation::Range(_) => CellValue::Error(CellError::Value),
+            Operation::Aggregate(a, args) => a.evaluate(args, cells),
+            Operation::Concat(o1, o2) => o1.evaluate(cells).concat(&o2.evaluate(cells)),
+            Operation::Compare(c, o1, o2) => c.apply(&o1.evaluate(cells), &o2.evaluate(cells)),
+            Operation::Logical(l, args) => l.evaluate(args, cells),
+            Operation::Lookup(l, args) => l.evaluate(args, cells),
+            Operation::Call(Some(function), args) => {
+                let args: Vec<CellValue> = args.iter().map(|o| o.evaluate(cells)).collect();
+                function::call(&**function, &args)
+            }
+            Operation::Call(None, _) => CellValue::Error(CellError::Name),
+        }
+    }
 }
@@ -0,0 +1,352 @@
+//! Import and export of CSV, TSV and similar delimited text.
+//!
+//! Fields may be enclosed in quotes, which allows them to contain delimiters and line breaks.
+//! Inside quotes a doubled quote stands for a single one, e.g. `"say ""hi"""`.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::cell;
+use crate::table::{Coord, Operation, Table};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsvOptions {
+    /// separates the fields of a record
+    pub delimiter: char,
+    /// encloses fields containing delimiters, quotes or line breaks
+    pub quote: char,
+    /// the first record is a header, whose fields are imported as text even if they look like
+    /// numbers. Has no effect on export
+    pub header: bool,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        CsvOptions {
+            delimiter: ',',
+            quote: '"',
+            header: false,
+        }
+    }
+}
+
+impl CsvOptions {
+    /// Tab separated values
+    pub fn tsv() -> CsvOptions {
+        CsvOptions {
+            delimiter: '\t',
+            ..CsvOptions::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CsvError {
+    Io(io::Error),
+    /// the quoted field starting in the given line (starting at 1) is not closed
+    UnterminatedQuote(usize),
+    /// the given record (starting at 1) would be imported past the last row or column
+    OutOfBounds(usize),
+}
+
+impl fmt::Display for CsvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsvError::Io(err) => write!(f, "{}", err),
+            CsvError::UnterminatedQuote(line) => write!(f, "line {}: unterminated quote", line),
+            CsvError::OutOfBounds(record) => {
+                write!(f, "record {}: past the last row or column", record)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CsvError {}
+
+impl From<io::Error> for CsvError {
+    fn from(err: io::Error) -> CsvError {
+        CsvError::Io(err)
+    }
+}
+
+/// Insert the records of `reader` into `table`, one row per record, starting at `origin`.
+/// Fields which are numbers become `Operation::Value`, all others `Operation::Text`.
+/// Empty fields clear their cell. The import can be undone in one step.
+/// Fails without changing `table` if a field would be past the last row or column
+pub fn import<R: Read>(
+    table: &mut Table,
+    mut reader: R,
+    origin: Coord,
+    options: &CsvOptions,
+) -> Result<(), CsvError> {
+    let mut input = String::new();
+    reader.read_to_string(&mut input)?;
+
+    let records = records(&input, options)?;
+    // every field has to fit before the table changes
+    for (y, record) in records.iter().enumerate() {
+        if field_coord(origin, record.len().saturating_sub(1), y).is_none() {
+            return Err(CsvError::OutOfBounds(y + 1));
+        }
+    }
+
+    table.begin_transaction();
+    for (y, record) in records.into_iter().enumerate() {
+        let is_header = options.header && y == 0;
+        for (x, field) in record.into_iter().enumerate() {
+            let coord = field_coord(origin, x, y).expect("field outside of the table");
+            if field.is_empty() {
+                table.remove(&coord);
+                continue;
+            }
+
+            let op = match cell::parse_number(&field) {
+                Some(v) if !is_header => Operation::Value(v),
+                _ => Operation::Text(field),
+            };
+            table
+                .insert(coord, op)
+                .expect("literals can't close a circular reference");
+        }
+    }
+    table.end_transaction();
+
+    Ok(())
+}
+
+/// Cell of the field `x` of record `y`, if it's inside of the table
+fn field_coord(origin: Coord, x: usize, y: usize) -> Option<Coord> {
+    let x = origin.0.checked_add(u32::try_from(x).ok()?)?;
+    let y = origin.1.checked_add(u32::try_from(y).ok()?)?;
+    Some((x, y))
+}
+
+/// Write the values of all cells from `A1` to the last used row and column of `table`, see `export_range`
+pub fn export<W: Write>(table: &Table, writer: &mut W, options: &CsvOptions) -> io::Result<()> {
+    let used = table
+        .iter()
+        .filter(|(_, cell)| !cell.is_empty())
+        .map(|(coord, _)| *coord);
+    let end = used.fold(None, |end: Option<Coord>, (x, y)| match end {
+        Some((ex, ey)) => Some((ex.max(x), ey.max(y))),
+        None => Some((x, y)),
+    });
+
+    match end {
+        Some(end) => export_range(table, writer, &(1, 1), &end, options),
+        None => Ok(()),
+    }
+}
+
+/// Write the values of the rectangle spanned by the corners `from` and `to`, one record per row.
+/// Empty cells are written as empty fields
+pub fn export_range<W: Write>(
+    table: &Table,
+    writer: &mut W,
+    from: &Coord,
+    to: &Coord,
+    options: &CsvOptions,
+) -> io::Result<()> {
+    let (x1, x2) = (from.0.min(to.0), from.0.max(to.0));
+    let (y1, y2) = (from.1.min(to.1), from.1.max(to.1));
+
+    for y in y1..=y2 {
+        let fields: Vec<String> = (x1..=x2)
+            .map(|x| {
+                let value = table
+                    .get_value_of_cell(&(x, y))
+                    .map(|v| v.to_string())
+                    .unwrap_or_default();
+                quote(&value, options)
+            })
+            .collect();
+        writeln!(writer, "{}", fields.join(&options.delimiter.to_string()))?;
+    }
+
+    Ok(())
+}
+
+/// `field`, enclosed in quotes if necessary
+fn quote(field: &str, options: &CsvOptions) -> String {
+    let needs_quotes = field
+        .chars()
+        .any(|c| c == options.delimiter || c == options.quote || c == '\n' || c == '\r');
+    if !needs_quotes {
+        return field.to_string();
+    }
+
+    let q = options.quote.to_string();
+    format!("{}{}{}", q, field.replace(&q, &q.repeat(2)), q)
+}
+
+/// Split `input` into records and their fields
+fn records(input: &str, options: &CsvOptions) -> Result<Vec<Vec<String>>, CsvError> {
+    let mut records = vec![];
+    let mut record = vec![];
+    let mut field = String::new();
+    let mut line = 1;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c == options.quote && field.is_empty() {
+            let start = line;
+            loop {
+                match chars.next() {
+                    Some(c) if c == options.quote => {
+                        // a doubled quote is a literal quote, a single one ends the field
+                        if chars.peek() == Some(&options.quote) {
+                            chars.next();
+                            field.push(c);
+                        } else {
+                            break;
+                        }
+                    }
+                    Some(c) => {
+                        if c == '\n' {
+                            line += 1;
+                        }
+                        field.push(c);
+                    }
+                    None => return Err(CsvError::UnterminatedQuote(start)),
+                }
+            }
+        } else if c == options.delimiter {
+            record.push(std::mem::take(&mut field));
+        } else if c == '\n' || c == '\r' {
+            if c == '\r' && chars.peek() == Some(&'\n') {
+                chars.next();
+            }
+            line += 1;
+            record.push(std::mem::take(&mut field));
+            records.push(std::mem::take(&mut record));
+        } else {
+            field.push(c);
+        }
+    }
+
+    // the last line doesn't need a line break
+    if !field.is_empty() || !record.is_empty() {
+        record.push(field);
+        records.push(record);
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::CellValue;
+
+    fn text(s: &str) -> Option<CellValue> {
+        Some(CellValue::Text(s.to_string()))
+    }
+
+    #[test]
+    fn split_records() {
+        let options = CsvOptions::default();
+        assert_eq!(
+            records("a,b\r\n1,\"x,\"\"y\"\"\nz\"\n\n,", &options).unwrap(),
+            vec![
+                vec!["a", "b"],
+                vec!["1", "x,\"y\"\nz"],
+                vec![""],
+                vec!["", ""],
+            ]
+        );
+        assert!(records("", &options).unwrap().is_empty());
+        assert!(matches!(
+            records("a\n\"b\n", &options),
+            Err(CsvError::UnterminatedQuote(2))
+        ));
+    }
+
+    #[test]
+    fn import_fields() {
+        let mut table = Table::new();
+        table.insert((2, 3), Operation::Value(7.)).unwrap();
+
+        let input = "year\tamount\n2020\t 1.5 \n2021\t\n";
+        let options = CsvOptions {
+            header: true,
+            ..CsvOptions::tsv()
+        };
+        import(&mut table, input.as_bytes(), (1, 1), &options).unwrap();
+
+        assert_eq!(table.get_value_of_cell(&(1, 1)), text("year"));
+        assert_eq!(
+            table.get_value_of_cell(&(1, 2)),
+            Some(CellValue::Num(2020.))
+        );
+        assert_eq!(table.get_value_of_cell(&(2, 2)), Some(CellValue::Num(1.5)));
+        // empty fields clear their cell
+        assert_eq!(table.get_value_of_cell(&(2, 3)), None);
+
+        let mut table = Table::new();
+        import(&mut table, "2020\tnan".as_bytes(), (2, 2), &options).unwrap();
+        assert_eq!(table.get_value_of_cell(&(2, 2)), text("2020"));
+        assert_eq!(table.get_value_of_cell(&(3, 2)), text("nan"));
+
+        // fields past the last row or column import nothing
+        let mut table = Table::new();
+        let result = import(
+            &mut table,
+            "1\n2\n3".as_bytes(),
+            (1, u32::MAX - 1),
+            &options,
+        );
+        assert!(matches!(result, Err(CsvError::OutOfBounds(3))));
+        let result = import(
+            &mut table,
+            "1\t2\t3".as_bytes(),
+            (u32::MAX - 1, 1),
+            &options,
+        );
+        assert!(matches!(result, Err(CsvError::OutOfBounds(1))));
+        assert!(table.iter().next().is_none());
+        import(
+            &mut table,
+            "1\t2".as_bytes(),
+            (u32::MAX - 1, u32::MAX),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(table.get_value_of_cell(&(u32::MAX, u32::MAX)), text("2"));
+    }
+
+    #[test]
+    fn export_values() {
+        let mut table = Table::new();
+        table
+            .insert((1, 1), Operation::Text("a;b".to_string()))
+            .unwrap();
+        table
+            .insert(
+                (3, 2),
+                Operation::Add(
+                    Box::new(Operation::Cell((1, 3))),
+                    Box::new(Operation::Value(1.)),
+                ),
+            )
+            .unwrap();
+
+        let options = CsvOptions {
+            delimiter: ';',
+            quote: '\'',
+            header: false,
+        };
+        let mut out = vec![];
+        export(&table, &mut out, &options).unwrap();
+        // the empty, referenced cell A3 doesn't count as used
+        assert_eq!(String::from_utf8(out).unwrap(), "'a;b';;\n;;1\n");
+
+        let mut out = vec![];
+        export_range(&table, &mut out, &(3, 2), &(2, 1), &CsvOptions::default()).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), ",\n,1\n");
+
+        let mut out = vec![];
+        export(&Table::new(), &mut out, &options).unwrap();
+        assert!(out.is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+//! Native file format of tabcel workbooks.
+//!
+//! A workbook is a UTF-8 text file. The first line is the header `tabcel <version>`,
+//! every following line is one entry:
+//!
+//! ```text
+//! tabcel 1
+//! width B 20
+//! cell A1 42
+//! cell A2 =A1 * 2
+//! cell B1 some text
+//! format A2 fixed 2
+//! ```
+//!
+//! - `width <column> <characters>`: width of a column which doesn't use the default width
+//! - `cell <address> <input>`: content of a cell as typed in, see `parser::parse_input`.
+//!   Everything after the single space following the address belongs to the input.
+//!   `\`, line feeds and carriage returns are escaped as `\\`, `\n` and `\r`
+//! - `format <address> <format>`: display format of a cell, see `format::Format`
+//!
+//! Empty lines and lines starting with `#` are ignored.
+//! Files with a version newer than `VERSION` are rejected.
+
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+use crate::address::{column_name, column_number, Address};
+use crate::format::Format;
+use crate::parser::{parse_input, to_input, ParseError};
+use crate::table::{Coord, Table, TableError};
+
+/// Version of the format written by `write`
+pub const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum FileError {
+    Io(io::Error),
+    /// the file doesn't start with `tabcel <version>`
+    MissingHeader,
+    /// the file was written by a newer version of tabcel
+    UnsupportedVersion(u32),
+    /// the line with the given number (starting at 1) is no valid entry
+    InvalidLine(usize),
+    /// the input of the cell in the given line can't be parsed
+    Parse(usize, ParseError),
+    /// the cell in the given line can't be inserted
+    Table(usize, TableError),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileError::Io(err) => write!(f, "{}", err),
+            FileError::MissingHeader => write!(f, "not a tabcel file"),
+            FileError::UnsupportedVersion(v) => write!(f, "unsupported file version {}", v),
+            FileError::InvalidLine(line) => write!(f, "line {}: invalid entry", line),
+            FileError::Parse(line, err) => write!(f, "line {}: {}", line, err),
+            FileError::Table(line, err) => write!(f, "line {}: {}", line, err),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+impl From<io::Error> for FileError {
+    fn from(err: io::Error) -> FileError {
+        FileError::Io(err)
+    }
+}
+
+/// Write `table` to `writer`. Entries are sorted, so that equal tables give equal files
+pub fn write<W: Write>(table: &Table, writer: &mut W) -> io::Result<()> {
+    writeln!(writer, "tabcel {}", VERSION)?;
+
+    let mut widths: Vec<(u32, u16)> = table.column_widths().map(|(c, w)| (*c, *w)).collect();
+    widths.sort_unstable();
+    for (col, width) in widths {
+        writeln!(writer, "width {} {}", column_name(col), width)?;
+    }
+
+    let mut cells: Vec<_> = table.operations().collect();
+    cells.sort_unstable_by_key(|(coord, _)| row_major(coord));
+    for (coord, op) in cells {
+        writeln!(
+            writer,
+            "cell {} {}",
+            Address::new(*coord),
+            escape(&to_input(op))
+        )?;
+    }
+
+    let mut formats: Vec<_> = table.formats().collect();
+    formats.sort_unstable_by_key(|(coord, _)| row_major(coord));
+    for (coord, format) in formats {
+        writeln!(writer, "format {} {}", Address::new(*coord), format)?;
+    }
+
+    Ok(())
+}
+
+/// Read a table written by `write`
+pub fn read<R: BufRead>(reader: R) -> Result<Table, FileError> {
+    let mut lines = reader.lines();
+
+    let header = lines.next().ok_or(FileError::MissingHeader)??;
+    let version = header
+        .strip_prefix("tabcel ")
+        .and_then(|v| v.trim().parse::<u32>().ok())
+        .ok_or(FileError::MissingHeader)?;
+    if version > VERSION {
+        return Err(FileError::UnsupportedVersion(version));
+    }
+
+    let mut table = Table::new();
+    for (i, line) in lines.enumerate() {
+        // the header is line 1
+        let number = i + 2;
+        let line = line?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let invalid = || FileError::InvalidLine(number);
+        let mut fields = line.splitn(3, ' ');
+        let kind = fields.next().ok_or_else(invalid)?;
+        let target = fields.next().ok_or_else(invalid)?;
+        let rest = fields.next().ok_or_else(invalid)?;
+
+        match kind {
+            "width" => {
+                let col = column_number(target).ok_or_else(invalid)?;
+                let width = rest.trim().parse::<u16>().map_err(|_| invalid())?;
+                table.set_column_width(col, Some(width));
+            }
+            "cell" => {
+                let coord = cell(target).ok_or_else(invalid)?;
+                let input = unescape(rest).ok_or_else(invalid)?;
+                let op = parse_input(&input).map_err(|e| FileError::Parse(number, e))?;
+                table
+                    .insert(coord, op)
+                    .map_err(|e| FileError::Table(number, e))?;
+            }
+            "format" => {
+                let coord = cell(target).ok_or_else(invalid)?;
+                let format = rest.parse::<Format>().map_err(|_| invalid())?;
+                table.set_format(coord, format);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    // loading is no change which can be undone
+    table.clear_history();
+    Ok(table)
+}
+
+fn cell(address: &str) -> Option<Coord> {
+    address.parse::<Address>().ok().map(|a| a.coord())
+}
+
+/// key to sort cells row by row
+fn row_major(coord: &Coord) -> (u32, u32) {
+    (coord.1, coord.0)
+}
+
+fn escape(input: &str) -> String {
+    input
+        .replace('\\', "\\\\")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Inverse of `escape`. `None` if `input` contains an unknown escape sequence
+fn unescape(input: &str) -> Option<String> {
+    let mut unescaped = String::with_capacity(input.len());
+    let mut chars = input.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => unescaped.push('\\'),
+            'n' => unescaped.push('\n'),
+            'r' => unescaped.push('\r'),
+            _ => return None,
+        }
+    }
+
+    Some(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cell::{CellError, CellValue};
+    use crate::table::Operation;
+
+    fn to_string(table: &Table) -> String {
+        let mut out = vec![];
+        write(table, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn from_str(s: &str) -> Result<Table, FileError> {
+        read(s.as_bytes())
+    }
+
+    #[test]
+    fn write_and_read() {
+        let mut table = Table::new();
+        table.insert((1, 2), Operation::Value(2.5)).unwrap();
+        table
+            .insert(
+                (1, 1),
+                Operation::Mul(
+                    Box::new(Operation::Cell((1, 2))),
+                    Box::new(Operation::Value(2.)),
+                ),
+            )
+            .unwrap();
+        table
+            .insert((2, 1), Operation::Text(" two\nlines \\ ".to_string()))
+            .unwrap();
+        table.set_column_width(2, Some(20));
+        table.set_format((1, 1), Format::Fixed(2));
+
+        let written = to_string(&table);
+        assert_eq!(
+            written,
+            "tabcel 1\n\
+             width B 20\n\
+             cell A1 =A2*2\n\
+             cell B1  two\\nlines \\\\ \n\
+             cell A2 2.5\n\
+             format A1 fixed 2\n"
+        );
+
+        let read = from_str(&written).unwrap();
+        assert_eq!(read.get_value_of_cell(&(1, 1)), Some(CellValue::Num(5.)));
+        assert_eq!(
+            read.get_value_of_cell(&(2, 1)),
+            Some(CellValue::Text(" two\nlines \\ ".to_string()))
+        );
+        assert_eq!(read.get_column_width(2), Some(20));
+        assert_eq!(read.get_format(&(1, 1)), Format::Fixed(2));
+        assert_eq!(to_string(&read), written);
+    }
+
+    #[test]
+    fn write_and_read_infinite_numbers() {
+        let mut table = Table::new();
+        table
+            .insert(
+                (1, 1),
+                Operation::Add(
+                    Box::new(Operation::Value(f64::INFINITY)),
+                    Box::new(Operation::Value(1.)),
+                ),
+            )
+            .unwrap();
+        table.insert((1, 2), Operation::Value(f64::NAN)).unwrap();
+
+        let written = to_string(&table);
+        assert_eq!(
+            written,
+            "tabcel 1\n\
+             cell A1 =#NUM!+1\n\
+             cell A2 =#NUM!\n"
+        );
+
+        let read = from_str(&written).unwrap();
+        for coord in &[(1, 1), (1, 2)] {
+            assert_eq!(
+                read.get_value_of_cell(coord),
+                Some(CellValue::Error(CellError::Num))
+            );
+        }
+        assert_eq!(to_string(&read), written);
+    }
+
+    #[test]
+    fn read_errors() {
+        assert!(matches!(from_str(""), Err(FileError::MissingHeader)));
+        assert!(matches!(from_str("a,b\n"), Err(FileError::MissingHeader)));
+        assert!(matches!(
+            from_str("tabcel 2\n"),
+            Err(FileError::UnsupportedVersion(2))
+        ));
+        assert!(matches!(
+            from_str("tabcel 1\n\n# comment\ncell A1\n"),
+            Err(FileError::InvalidLine(4))
+        ));
+        assert!(matches!(
+            from_str("tabcel 1\ncell A1 x\\ty\n"),
+            Err(FileError::InvalidLine(2))
+        ));
+        assert!(matches!(
+            from_str("tabcel 1\ncell A1 =1 +\n"),
+            Err(FileError::Parse(2, _))
+        ));
+        assert!(matches!(
+            from_str("tabcel 1\ncell A1 =B1\ncell B1 =A1\n"),
+            Err(FileError::Table(3, TableError::Cycle(_)))
+        ));
+    }
+}
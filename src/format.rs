@@ -0,0 +1,142 @@
+//! Display formats of cells, e.g. numbers with two decimals
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cell::CellValue;
+
+/// Largest number of decimals of `Format::Fixed` and `Format::Percent`
+pub const MAX_DECIMALS: usize = 20;
+
+/// How the value of a cell is displayed. Only affects numbers
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    /// numbers as short as possible
+    #[default]
+    General,
+    /// numbers with the given number of decimals, at most `MAX_DECIMALS`
+    Fixed(usize),
+    /// numbers multiplied by 100 with the given number of decimals, at most `MAX_DECIMALS`,
+    /// and a `%` sign
+    Percent(usize),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FormatError {
+    pub input: String,
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid format '{}'", self.input)
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl Format {
+    /// `value` as it should be shown to the user
+    pub fn apply(&self, value: &CellValue) -> String {
+        match (self, value) {
+            (Format::Fixed(decimals), CellValue::Num(v)) => {
+                format!("{:.*}", (*decimals).min(MAX_DECIMALS), v)
+            }
+            (Format::Percent(decimals), CellValue::Num(v)) => {
+                format!("{:.*}%", (*decimals).min(MAX_DECIMALS), v * 100.)
+            }
+            _ => value.to_string(),
+        }
+    }
+}
+
+/// Inverse of `Display`, e.g. `general`, `fixed 2` or `percent 0` (case insensitive).
+/// More than `MAX_DECIMALS` decimals are invalid
+impl FromStr for Format {
+    type Err = FormatError;
+
+    fn from_str(s: &str) -> Result<Format, FormatError> {
+        let err = || FormatError {
+            input: s.to_string(),
+        };
+
+        let mut words = s.split_whitespace();
+        let kind = words.next().ok_or_else(err)?.to_ascii_lowercase();
+        let decimals = match words.next() {
+            Some(d) => match d.parse::<usize>() {
+                Ok(d) if d <= MAX_DECIMALS => Some(d),
+                _ => return Err(err()),
+            },
+            None => None,
+        };
+        if words.next().is_some() {
+            return Err(err());
+        }
+
+        match (kind.as_str(), decimals) {
+            ("general", None) => Ok(Format::General),
+            ("fixed", Some(d)) => Ok(Format::Fixed(d)),
+            ("percent", Some(d)) => Ok(Format::Percent(d)),
+            _ => Err(err()),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::General => write!(f, "general"),
+            Format::Fixed(d) => write!(f, "fixed {}", d),
+            Format::Percent(d) => write!(f, "percent {}", d),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_formats() {
+        let v = CellValue::Num(0.1234);
+        assert_eq!(Format::General.apply(&v), "0.1234");
+        assert_eq!(Format::Fixed(2).apply(&v), "0.12");
+        assert_eq!(Format::Fixed(0).apply(&CellValue::Num(2.5)), "2");
+        assert_eq!(Format::Percent(1).apply(&v), "12.3%");
+        assert_eq!(
+            Format::Fixed(2).apply(&CellValue::Text("abc".to_string())),
+            "abc"
+        );
+        assert_eq!(
+            Format::Fixed(100_000).apply(&CellValue::Num(0.5)),
+            Format::Fixed(MAX_DECIMALS).apply(&CellValue::Num(0.5))
+        );
+    }
+
+    #[test]
+    fn parse_formats() {
+        for format in &[Format::General, Format::Fixed(3), Format::Percent(0)] {
+            assert_eq!(format.to_string().parse(), Ok(*format));
+        }
+        assert_eq!(" Fixed  2 ".parse(), Ok(Format::Fixed(2)));
+
+        assert_eq!("percent 20".parse(), Ok(Format::Percent(MAX_DECIMALS)));
+
+        for invalid in &[
+            "",
+            "fixed",
+            "general 2",
+            "fixed -1",
+            "fixed 2 3",
+            "date",
+            "fixed 21",
+            "percent 100000",
+        ] {
+            assert_eq!(
+                invalid.parse::<Format>(),
+                Err(FormatError {
+                    input: invalid.to_string()
+                })
+            );
+        }
+    }
+}
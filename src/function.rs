@@ -0,0 +1,381 @@
+//! Functions which can be called by name from formulas, e.g. `=ROUND(A1 / 3, 2)`.
+//!
+//! | function                   | result                                                  |
+//! |----------------------------|---------------------------------------------------------|
+//! | `SIN`, `COS`, `TAN`        | trigonometric functions of an angle in radians          |
+//! | `ASIN`, `ACOS`, `ATAN`     | their inverses                                          |
+//! | `ATAN2(x, y)`              | angle of the point `(x, y)`                             |
+//! | `EXP`, `LN`, `LOG10`       | `e^x`, natural and decimal logarithm                    |
+//! | `LOG(x, [base])`           | logarithm to `base`, which defaults to 10               |
+//! | `SQRT`, `ABS`, `SIGN`      | square root, absolute value, sign as -1, 0 or 1         |
+//! | `FLOOR`, `CEILING`         | next integer towards negative or positive infinity      |
+//! | `ROUND(x, [digits])`       | `x` rounded half away from zero to `digits` decimals    |
+//! | `TRUNC(x, [digits])`       | `x` truncated towards zero to `digits` decimals         |
+//! | `PI()`                     | π                                                       |
+//!
+//! Arguments are coerced like operands of arithmetic, where empty cells count as 0.
+//! Calls with the wrong number of arguments evaluate to `#VALUE!`,
+//! arguments outside of the domain of a function to `#NUM!`.
+//!
+//! The text functions, e.g. `LEN` or `SUBSTITUTE`, are listed in `text`.
+//! The logical functions `IF`, `AND`, `OR`, `XOR`, `NOT`, `IFERROR`, `IFS` and `SWITCH` are
+//! no `Function`s, as they only evaluate the arguments they need, see `cell::Logical`.
+//! Neither are the lookup functions `VLOOKUP`, `HLOOKUP`, `INDEX`, `MATCH` and `XLOOKUP`,
+//! which take ranges as arguments, see `cell::Lookup`.
+//!
+//! Embedding applications can add their own functions by implementing `Function` and passing
+//! them to `Table::register_function`. They can replace the functions listed here,
+//! but not `SUM`, `AVERAGE`, `MIN`, `MAX`, `COUNT` or the logical and lookup functions,
+//! see `parser::is_reserved`.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt;
+use std::sync::Arc;
+
+use crate::cell::{CellError, CellValue};
+use crate::text;
+
+/// Number of arguments a function accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arity {
+    pub min: usize,
+    /// `None` for any number of arguments
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    /// Exactly `n` arguments
+    pub const fn exactly(n: usize) -> Arity {
+        Arity {
+            min: n,
+            max: Some(n),
+        }
+    }
+
+    /// Between `min` and `max` arguments
+    pub const fn between(min: usize, max: usize) -> Arity {
+        Arity {
+            min,
+            max: Some(max),
+        }
+    }
+
+    pub fn accepts(&self, count: usize) -> bool {
+        self.min <= count && self.max.map_or(true, |max| count <= max)
+    }
+}
+
+/// A function which can be called by name from formulas. Implement it to add custom functions
+/// to a table, see `Table::register_function`
+pub trait Function: Send + Sync {
+    /// Name as used in formulas. Names are case insensitive
+    fn name(&self) -> &str;
+
+    fn arity(&self) -> Arity;
+
+    /// Result of the function for the values of its arguments, which are as many as
+    /// `arity` accepts. Empty cells are `CellValue::Unity`
+    fn evaluate(&self, args: &[CellValue]) -> CellValue;
+}
+
+impl fmt::Debug for dyn Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Function({})", self.name())
+    }
+}
+
+/// Functions are equal if they are the same implementation
+impl PartialEq for dyn Function {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::addr_eq(self, other)
+    }
+}
+
+/// Apply `function` to `args`, or `#VALUE!` if the number of arguments is wrong
+pub fn call(function: &dyn Function, args: &[CellValue]) -> CellValue {
+    if !function.arity().accepts(args.len()) {
+        return CellValue::Error(CellError::Value);
+    }
+    function.evaluate(args)
+}
+
+/// Functions known to a table, by name
+#[derive(Clone)]
+pub struct Functions {
+    /// keys are upper case
+    functions: HashMap<String, Arc<dyn Function>>,
+}
+
+impl Default for Functions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Functions {
+    /// All built-in functions
+    pub fn new() -> Functions {
+        let mut functions = Functions {
+            functions: HashMap::new(),
+        };
+        for builtin in BUILTINS {
+            functions.register(Arc::new(*builtin));
+        }
+        for function in text::FUNCTIONS {
+            functions.register(Arc::new(*function));
+        }
+        functions
+    }
+
+    /// Add `function`, replacing the function with the same name, if any
+    pub fn register(&mut self, function: Arc<dyn Function>) {
+        self.functions
+            .insert(function.name().to_ascii_uppercase(), function);
+    }
+
+    /// The function called `name`, ignoring case
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Function>> {
+        self.functions.get(&name.to_ascii_uppercase())
+    }
+}
+
+/// Function of numbers built into tabcel
+#[derive(Clone, Copy)]
+struct Builtin {
+    name: &'static str,
+    arity: Arity,
+    apply: fn(&[f64]) -> Result<f64, CellError>,
+}
+
+impl Function for Builtin {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    fn evaluate(&self, args: &[CellValue]) -> CellValue {
+        let numbers: Result<Vec<f64>, CellError> = args.iter().map(|a| a.to_number()).collect();
+        match numbers.and_then(|n| (self.apply)(&n)) {
+            Ok(v) if v.is_finite() => CellValue::Num(v),
+            Ok(_) => CellValue::Error(CellError::Num),
+            Err(e) => CellValue::Error(e),
+        }
+    }
+}
+
+/// `x` rounded with `round` to the optional argument `digits` decimals, which defaults to 0.
+/// Numbers which are more precise than `f64` stay as they are
+fn round_to(args: &[f64], round: fn(f64) -> f64) -> f64 {
+    // 10^308 is close to the largest `f64`, 10^-308 to the smallest normal one
+    let digits = args.get(1).copied().unwrap_or(0.).clamp(-308., 308.);
+    let scale = 10f64.powi(digits as i32);
+    let scaled = args[0] * scale;
+    // from 2^53 on every `f64` is an integer, so there is nothing left to round
+    if !scaled.is_finite() || scaled.abs() >= 2f64.powi(53) {
+        return args[0];
+    }
+    round(scaled) / scale
+}
+
+/// `x` rounded with `round` to a multiple of the optional argument `significance`, which defaults to 1
+fn multiple(args: &[f64], round: fn(f64) -> f64) -> Result<f64, CellError> {
+    match args.get(1).copied().unwrap_or(1.) {
+        0. => Err(CellError::Div0),
+        s => Ok(round(args[0] / s) * s),
+    }
+}
+
+fn logarithm(x: f64, base: f64) -> Result<f64, CellError> {
+    if x <= 0. || base <= 0. {
+        return Err(CellError::Num);
+    }
+    // the dedicated functions are exact for powers of their base
+    match base {
+        1. => Err(CellError::Div0),
+        2. => Ok(x.log2()),
+        10. => Ok(x.log10()),
+        _ => Ok(x.log(base)),
+    }
+}
+
+static BUILTINS: &[Builtin] = &[
+    Builtin {
+        name: "SIN",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(a[0].sin()),
+    },
+    Builtin {
+        name: "COS",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(a[0].cos()),
+    },
+    Builtin {
+        name: "TAN",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(a[0].tan()),
+    },
+    Builtin {
+        name: "ASIN",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(a[0].asin()),
+    },
+    Builtin {
+        name: "ACOS",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(a[0].acos()),
+    },
+    Builtin {
+        name: "ATAN",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(a[0].atan()),
+    },
+    Builtin {
+        name: "ATAN2",
+        arity: Arity::exactly(2),
+        apply: |a| match (a[0], a[1]) {
+            (x, y) if x == 0. && y == 0. => Err(CellError::Div0),
+            (x, y) => Ok(y.atan2(x)),
+        },
+    },
+    Builtin {
+        name: "EXP",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(a[0].exp()),
+    },
+    Builtin {
+        name: "LN",
+        arity: Arity::exactly(1),
+        apply: |a| logarithm(a[0], std::f64::consts::E),
+    },
+    Builtin {
+        name: "LOG10",
+        arity: Arity::exactly(1),
+        apply: |a| logarithm(a[0], 10.),
+    },
+    Builtin {
+        name: "LOG",
+        arity: Arity::between(1, 2),
+        apply: |a| logarithm(a[0], a.get(1).copied().unwrap_or(10.)),
+    },
+    Builtin {
+        name: "SQRT",
+        arity: Arity::exactly(1),
+        apply: |a| match a[0] {
+            x if x < 0. => Err(CellError::Num),
+            x => Ok(x.sqrt()),
+        },
+    },
+    Builtin {
+        name: "ABS",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(a[0].abs()),
+    },
+    Builtin {
+        name: "SIGN",
+        arity: Arity::exactly(1),
+        apply: |a| match a[0] {
+            0. => Ok(0.),
+            x => Ok(x.signum()),
+        },
+    },
+    Builtin {
+        name: "FLOOR",
+        arity: Arity::between(1, 2),
+        apply: |a| multiple(a, f64::floor),
+    },
+    Builtin {
+        name: "CEILING",
+        arity: Arity::between(1, 2),
+        apply: |a| multiple(a, f64::ceil),
+    },
+    Builtin {
+        name: "ROUND",
+        arity: Arity::between(1, 2),
+        apply: |a| Ok(round_to(a, f64::round)),
+    },
+    Builtin {
+        name: "TRUNC",
+        arity: Arity::between(1, 2),
+        apply: |a| Ok(round_to(a, f64::trunc)),
+    },
+    Builtin {
+        name: "PI",
+        arity: Arity::exactly(0),
+        apply: |_| Ok(PI),
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn function(name: &str) -> Arc<dyn Function> {
+        Functions::new().get(name).unwrap().clone()
+    }
+
+    fn call(name: &str, args: &[f64]) -> CellValue {
+        let args: Vec<CellValue> = args.iter().map(|v| CellValue::Num(*v)).collect();
+        super::call(&*function(name), &args)
+    }
+
+    #[test]
+    fn math_functions() {
+        assert_eq!(call("cos", &[0.]), CellValue::Num(1.));
+        assert_eq!(call("ATAN2", &[1., 1.]), CellValue::Num(PI / 4.));
+        assert_eq!(call("ln", &[1.]), CellValue::Num(0.));
+        assert_eq!(call("LOG", &[8., 2.]), CellValue::Num(3.));
+        assert_eq!(call("LOG", &[1000.]), CellValue::Num(3.));
+        assert_eq!(call("SQRT", &[9.]), CellValue::Num(3.));
+        assert_eq!(call("SIGN", &[-0.5]), CellValue::Num(-1.));
+        assert_eq!(call("FLOOR", &[-2.5]), CellValue::Num(-3.));
+        assert_eq!(call("CEILING", &[7., 5.]), CellValue::Num(10.));
+        assert_eq!(call("ROUND", &[2.5]), CellValue::Num(3.));
+        assert_eq!(call("ROUND", &[-1.2345, 2.]), CellValue::Num(-1.23));
+        assert_eq!(call("ROUND", &[1234., -2.]), CellValue::Num(1200.));
+        assert_eq!(call("TRUNC", &[-1.789, 1.]), CellValue::Num(-1.7));
+        assert_eq!(call("PI", &[]), CellValue::Num(PI));
+
+        // more digits than a number has leave it unchanged, fewer round it to 0
+        assert_eq!(call("ROUND", &[1.5, 400.]), CellValue::Num(1.5));
+        assert_eq!(call("ROUND", &[1e300, 10.]), CellValue::Num(1e300));
+        assert_eq!(call("TRUNC", &[-1.789, 1e9]), CellValue::Num(-1.789));
+        assert_eq!(call("ROUND", &[1234., -400.]), CellValue::Num(0.));
+        assert_eq!(call("ROUND", &[1e300, -2.]), CellValue::Num(1e300));
+        assert_eq!(call("TRUNC", &[5e307, -308.]), CellValue::Num(0.));
+        assert!(Functions::new().get("nope").is_none());
+    }
+
+    #[test]
+    fn function_errors() {
+        let num = CellValue::Error(CellError::Num);
+        assert_eq!(call("SQRT", &[-1.]), num);
+        assert_eq!(call("LN", &[0.]), num);
+        assert_eq!(call("ASIN", &[2.]), num);
+        assert_eq!(call("EXP", &[1000.]), num);
+        assert_eq!(call("LOG", &[2., 1.]), CellValue::Error(CellError::Div0));
+        assert_eq!(call("ATAN2", &[0., 0.]), CellValue::Error(CellError::Div0));
+
+        // wrong number of arguments
+        assert_eq!(call("COS", &[]), CellValue::Error(CellError::Value));
+        assert_eq!(call("PI", &[1.]), CellValue::Error(CellError::Value));
+
+        // coercion, the first error wins
+        let sin = function("SIN");
+        assert_eq!(sin.evaluate(&[CellValue::Unity]), CellValue::Num(0.));
+        assert_eq!(
+            sin.evaluate(&[CellValue::Text("x".to_string())]),
+            CellValue::Error(CellError::Value)
+        );
+        assert_eq!(
+            function("LOG").evaluate(&[
+                CellValue::Error(CellError::Ref),
+                CellValue::Error(CellError::Div0)
+            ]),
+            CellValue::Error(CellError::Ref)
+        );
+    }
+}
@@ -1,2 +1,10 @@
+pub mod address;
 pub mod cell;
+pub mod csv;
+pub mod file;
+pub mod format;
+pub mod function;
+pub mod operation;
+pub mod parser;
 pub mod table;
+pub mod text;
@@ -0,0 +1,197 @@
+//! Expression trees of cells.
+//!
+//! The table keeps the formulas as inserted, which reference other cells by their `Coord`.
+//! The cells evaluate the same formulas with references to their ids in `cell::Cells`.
+//! Both are an `Operation`, which only differs in the kind of its references, see `Reference`.
+//! `table::Operation` and `cell::Operation` name the two kinds.
+
+use std::fmt;
+use std::sync::Arc;
+
+use crate::cell::{Aggregate, CellError, CellId, CellRange, Comparison, Logical, Lookup};
+use crate::function::Function;
+use crate::table::Coord;
+
+/// Kind of references to other cells within an `Operation`
+pub trait Reference {
+    /// a single cell
+    type Cell: fmt::Debug + Clone + PartialEq;
+    /// a rectangular range of cells
+    type Range: fmt::Debug + Clone + PartialEq;
+    /// a function, see `function`
+    type Function: fmt::Debug + Clone + PartialEq;
+}
+
+/// References by position and functions by name, as written in formulas.
+/// Ranges are given by two corners
+#[derive(Debug, Clone, PartialEq)]
+pub struct ByCoord;
+
+impl Reference for ByCoord {
+    type Cell = Coord;
+    type Range = (Coord, Coord);
+    /// upper case name
+    type Function = String;
+}
+
+/// References to cells in `cell::Cells` and to the implementations of functions
+#[derive(Debug, Clone, PartialEq)]
+pub struct ById;
+
+impl Reference for ById {
+    type Cell = CellId;
+    type Range = CellRange;
+    /// `None` for unknown functions
+    type Function = Option<Arc<dyn Function>>;
+}
+
+/// Translates references of kind `F` into references of kind `T`, see `Operation::resolve`
+pub trait Resolver<F: Reference, T: Reference> {
+    fn cell(&mut self, cell: &F::Cell) -> T::Cell;
+    fn range(&mut self, range: &F::Range) -> T::Range;
+    fn function(&mut self, function: &F::Function) -> T::Function;
+}
+
+/// Expression tree of a cell. Every operand can itself be an `Operation`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation<R: Reference> {
+    Value(f64),
+    Text(String),
+    Bool(bool),
+    Error(CellError),
+    Cell(R::Cell),
+    Add(Box<Operation<R>>, Box<Operation<R>>),
+    Sub(Box<Operation<R>>, Box<Operation<R>>),
+    Mul(Box<Operation<R>>, Box<Operation<R>>),
+    Div(Box<Operation<R>>, Box<Operation<R>>),
+    Pow(Box<Operation<R>>, Box<Operation<R>>),
+    /// remainder of the floored division
+    Mod(Box<Operation<R>>, Box<Operation<R>>),
+    Neg(Box<Operation<R>>),
+    /// text of both operands joined, e.g. `A1 & " kg"`
+    Concat(Box<Operation<R>>, Box<Operation<R>>),
+    /// comparison of two values, e.g. `A1 <= 3`
+    Compare(Comparison, Box<Operation<R>>, Box<Operation<R>>),
+    /// rectangular range, e.g. `A1:C10`.
+    /// Only valid as argument of an `Aggregate`, `Logical` or `Lookup`, otherwise evaluates to `#VALUE!`
+    Range(R::Range),
+    Aggregate(Aggregate, Vec<Operation<R>>),
+    /// logical or conditional function, which only evaluates the arguments it needs
+    Logical(Logical, Vec<Operation<R>>),
+    /// lookup of values in ranges
+    Lookup(Lookup, Vec<Operation<R>>),
+    /// call of a function with its arguments
+    Call(R::Function, Vec<Operation<R>>),
+}
+
+impl<R: Reference> Operation<R> {
+    /// Call `f` with this operation and all its operands, depth first from left to right
+    pub fn visit<F: FnMut(&Operation<R>)>(&self, f: &mut F) {
+        f(self);
+        match self {
+            Operation::Value(_) | Operation::Text(_) | Operation::Bool(_) => (),
+            Operation::Error(_) | Operation::Cell(_) | Operation::Range(_) => (),
+            Operation::Add(o1, o2)
+            | Operation::Sub(o1, o2)
+            | Operation::Mul(o1, o2)
+            | Operation::Div(o1, o2)
+            | Operation::Pow(o1, o2)
+            | Operation::Mod(o1, o2)
+            | Operation::Concat(o1, o2)
+            | Operation::Compare(_, o1, o2) => {
+                o1.visit(f);
+                o2.visit(f);
+            }
+            Operation::Neg(o) => o.visit(f),
+            Operation::Aggregate(_, args)
+            | Operation::Logical(_, args)
+            | Operation::Lookup(_, args)
+            | Operation::Call(_, args) => {
+                for o in args {
+                    o.visit(f);
+                }
+            }
+        }
+    }
+
+    /// The same operation with all references translated by `resolver`
+    pub fn resolve<T: Reference, V: Resolver<R, T>>(&self, resolver: &mut V) -> Operation<T> {
+        let mut operand = |o: &Operation<R>| Box::new(o.resolve(resolver));
+        match self {
+            Operation::Value(v) => Operation::Value(*v),
+            Operation::Text(s) => Operation::Text(s.clone()),
+            Operation::Bool(b) => Operation::Bool(*b),
+            Operation::Error(e) => Operation::Error(*e),
+            Operation::Cell(c) => Operation::Cell(resolver.cell(c)),
+            Operation::Add(o1, o2) => Operation::Add(operand(o1), operand(o2)),
+            Operation::Sub(o1, o2) => Operation::Sub(operand(o1), operand(o2)),
+            Operation::Mul(o1, o2) => Operation::Mul(operand(o1), operand(o2)),
+            Operation::Div(o1, o2) => Operation::Div(operand(o1), operand(o2)),
+            Operation::Pow(o1, o2) => Operation::Pow(operand(o1), operand(o2)),
+            Operation::Mod(o1, o2) => Operation::Mod(operand(o1), operand(o2)),
+            Operation::Neg(o) => Operation::Neg(operand(o)),
+            Operation::Concat(o1, o2) => Operation::Concat(operand(o1), operand(o2)),
+            Operation::Compare(c, o1, o2) => Operation::Compare(*c, operand(o1), operand(o2)),
+            Operation::Range(r) => Operation::Range(resolver.range(r)),
+            Operation::Aggregate(a, args) => {
+                Operation::Aggregate(*a, args.iter().map(|o| o.resolve(resolver)).collect())
+            }
+            Operation::Logical(l, args) => {
+                Operation::Logical(*l, args.iter().map(|o| o.resolve(resolver)).collect())
+            }
+            Operation::Lookup(l, args) => {
+                Operation::Lookup(*l, args.iter().map(|o| o.resolve(resolver)).collect())
+            }
+            Operation::Call(f, args) => Operation::Call(
+                resolver.function(f),
+                args.iter().map(|o| o.resolve(resolver)).collect(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// moves all references one row down
+    struct Shift;
+
+    impl Resolver<ByCoord, ByCoord> for Shift {
+        fn cell(&mut self, (x, y): &Coord) -> Coord {
+            (*x, y + 1)
+        }
+
+        fn range(&mut self, (from, to): &(Coord, Coord)) -> (Coord, Coord) {
+            (self.cell(from), self.cell(to))
+        }
+
+        fn function(&mut self, name: &String) -> String {
+            name.clone()
+        }
+    }
+
+    #[test]
+    fn resolve_references() {
+        let op: Operation<ByCoord> = Operation::Aggregate(
+            Aggregate::Sum,
+            vec![
+                Operation::Range(((1, 1), (2, 3))),
+                Operation::Call("ABS".to_string(), vec![Operation::Cell((3, 1))]),
+                Operation::Text("A1".to_string()),
+            ],
+        );
+
+        assert_eq!(
+            op.resolve(&mut Shift),
+            Operation::Aggregate(
+                Aggregate::Sum,
+                vec![
+                    Operation::Range(((1, 2), (2, 4))),
+                    Operation::Call("ABS".to_string(), vec![Operation::Cell((3, 2))]),
+                    Operation::Text("A1".to_string()),
+                ],
+            )
+        );
+    }
+}
@@ -0,0 +1,969 @@
+//! Parser for the formula language, e.g. `=A1 + sin(B2)`.
+//!
+//! The grammar, from lowest to highest precedence:
+//!
+//! ```text
+//! formula := ['='] expr
+//! expr    := concat (('=' | '<>' | '<' | '<=' | '>' | '>=') concat)*
+//! concat  := sum ('&' sum)*
+//! sum     := term (('+' | '-') term)*
+//! term    := unary (('*' | '/' | '%') unary)*
+//! unary   := ('+' | '-') unary | power
+//! power   := primary ['^' unary]
+//! primary := number | string | bool | error | cell [':' cell] | call | '(' expr ')'
+//! call    := function '(' [expr (',' expr)*] ')'
+//! ```
+//!
+//! `%` is the modulo operator and `^` is right associative, so `-2^2` is `-4`
+//! and `2^3^2` is `2^9`.
+//! Comparisons result in `TRUE` or `FALSE`, e.g. `=A1 + 1 >= B1`.
+//! `&` joins the text of its operands, e.g. `="Total: " & SUM(A1:A3)`,
+//! up to `text::MAX_LENGTH` bytes.
+//!
+//! Cells are written in A1 notation (`A1`, `AA10`, `$B$3`), see `address::Address`.
+//! Two cells separated by `:` form a range, e.g. `SUM(A1:C10)`. All ranges of a formula
+//! together span at most `table::MAX_RANGE_SIZE` cells.
+//! Function names are case insensitive, see `function` for the available functions.
+//! Unknown functions and calls with the wrong number of arguments are no parse errors,
+//! they evaluate to `#NAME?` and `#VALUE!`.
+//! Errors can be written literally, e.g. `#REF!`.
+//! Strings are enclosed in double quotes, where `""` stands for a single `"`.
+//! Booleans are `TRUE` and `FALSE`.
+
+use std::fmt;
+
+use crate::address::Address;
+use crate::cell::{parse_number, Aggregate, CellError, Comparison, Logical, Lookup};
+use crate::table::{Coord, Operation};
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub kind: ParseErrorKind,
+    /// byte offset into the parsed input where the error occurred
+    pub position: usize,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ParseErrorKind {
+    UnexpectedCharacter(char),
+    UnexpectedToken,
+    UnexpectedEnd,
+    InvalidNumber,
+    UnterminatedString,
+    InvalidCell(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnexpectedCharacter(c) => write!(f, "unexpected character '{}'", c)?,
+            ParseErrorKind::UnexpectedToken => write!(f, "unexpected token")?,
+            ParseErrorKind::UnexpectedEnd => write!(f, "unexpected end of input")?,
+            ParseErrorKind::InvalidNumber => write!(f, "invalid number")?,
+            ParseErrorKind::UnterminatedString => write!(f, "unterminated string")?,
+            ParseErrorKind::InvalidCell(s) => write!(f, "invalid cell '{}'", s)?,
+        }
+        write!(f, " at position {}", self.position)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Parse `input` into an `Operation` that can be passed to `Table::insert`
+/// A leading `=` is optional
+pub fn parse(input: &str) -> Result<Operation, ParseError> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        end: input.len(),
+    };
+
+    if let Some(TokenKind::Equals) = parser.peek() {
+        parser.pos += 1;
+    }
+
+    let op = parser.expr()?;
+
+    match parser.tokens.get(parser.pos) {
+        Some(t) => Err(ParseError {
+            kind: ParseErrorKind::UnexpectedToken,
+            position: t.position,
+        }),
+        None => Ok(op),
+    }
+}
+
+/// Parse the raw content of a cell as typed by the user
+/// Input starting with `=` is a formula, everything else is a literal number, boolean or text
+pub fn parse_input(input: &str) -> Result<Operation, ParseError> {
+    if input.starts_with('=') {
+        return parse(input);
+    }
+
+    let trimmed = input.trim();
+    // words like `inf` or `NaN` are text, as are numbers too large to represent
+    match trimmed.parse::<f64>() {
+        Ok(v) if v.is_finite() => return Ok(Operation::Value(v)),
+        _ => (),
+    }
+    if trimmed.eq_ignore_ascii_case("true") {
+        return Ok(Operation::Bool(true));
+    }
+    if trimmed.eq_ignore_ascii_case("false") {
+        return Ok(Operation::Bool(false));
+    }
+
+    Ok(Operation::Text(input.to_string()))
+}
+
+/// Raw content of a cell with operation `op`, i.e. the inverse of `parse_input`
+pub fn to_input(op: &Operation) -> String {
+    match op {
+        Operation::Value(v) if v.is_finite() => v.to_string(),
+        Operation::Bool(_) => op.to_string(),
+        Operation::Text(s) => {
+            // text which would be read as something else has to be written as formula
+            match parse_input(s) {
+                Ok(Operation::Text(_)) => s.clone(),
+                _ => format!("={}", op),
+            }
+        }
+        _ => format!("={}", op),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Number(f64),
+    Str(String),
+    Error(CellError),
+    Ident(String),
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Caret,
+    Comma,
+    Colon,
+    LParen,
+    RParen,
+    Ampersand,
+    Equals,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(position, c)) = chars.peek() {
+        let kind = match c {
+            c if c.is_whitespace() => {
+                chars.next();
+                continue;
+            }
+            '0'..='9' | '.' => {
+                let mut end = position;
+                while let Some(&(i, c)) = chars.peek() {
+                    // allow a sign directly after the exponent marker, e.g. `1e-3`
+                    let is_exponent_sign = (c == '+' || c == '-')
+                        && matches!(input[..i].chars().last(), Some('e') | Some('E'));
+                    if !(c.is_ascii_digit() || c == '.' || c == 'e' || c == 'E' || is_exponent_sign)
+                    {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                // numbers too large to represent are invalid, too
+                let number = parse_number(&input[position..end]).ok_or(ParseError {
+                    kind: ParseErrorKind::InvalidNumber,
+                    position,
+                })?;
+                tokens.push(Token {
+                    kind: TokenKind::Number(number),
+                    position,
+                });
+                continue;
+            }
+            c if c.is_ascii_alphabetic() || c == '_' || c == '$' => {
+                let mut end = position;
+                while let Some(&(i, c)) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_' || c == '$') {
+                        break;
+                    }
+                    end = i + c.len_utf8();
+                    chars.next();
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Ident(input[position..end].to_string()),
+                    position,
+                });
+                continue;
+            }
+            '"' => {
+                chars.next();
+                let mut string = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => {
+                            // `""` is an escaped quote, a single `"` ends the string
+                            if let Some(&(_, '"')) = chars.peek() {
+                                chars.next();
+                                string.push('"');
+                            } else {
+                                break;
+                            }
+                        }
+                        Some((_, c)) => string.push(c),
+                        None => {
+                            return Err(ParseError {
+                                kind: ParseErrorKind::UnterminatedString,
+                                position,
+                            })
+                        }
+                    }
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Str(string),
+                    position,
+                });
+                continue;
+            }
+            '#' => {
+                let errors = [
+                    CellError::Ref,
+                    CellError::Div0,
+                    CellError::Value,
+                    CellError::Name,
+                    CellError::Num,
+                    CellError::NA,
+                ];
+                let error = errors
+                    .iter()
+                    .find(|e| input[position..].starts_with(&e.to_string()))
+                    .ok_or(ParseError {
+                        kind: ParseErrorKind::UnexpectedCharacter('#'),
+                        position,
+                    })?;
+                let len = error.to_string().len();
+                while chars.peek().is_some_and(|&(i, _)| i < position + len) {
+                    chars.next();
+                }
+                tokens.push(Token {
+                    kind: TokenKind::Error(*error),
+                    position,
+                });
+                continue;
+            }
+            '+' => TokenKind::Plus,
+            '-' => TokenKind::Minus,
+            '*' => TokenKind::Star,
+            '/' => TokenKind::Slash,
+            '%' => TokenKind::Percent,
+            '^' => TokenKind::Caret,
+            ',' => TokenKind::Comma,
+            ':' => TokenKind::Colon,
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            '&' => TokenKind::Ampersand,
+            '=' => TokenKind::Equals,
+            '<' | '>' => {
+                chars.next();
+                let kind = match (c, chars.peek().map(|&(_, c)| c)) {
+                    ('<', Some('=')) => TokenKind::LessEqual,
+                    ('<', Some('>')) => TokenKind::NotEqual,
+                    ('>', Some('=')) => TokenKind::GreaterEqual,
+                    ('<', _) => TokenKind::Less,
+                    _ => TokenKind::Greater,
+                };
+                if matches!(kind, TokenKind::Less | TokenKind::Greater) {
+                    tokens.push(Token { kind, position });
+                    continue;
+                }
+                // the second character is consumed below
+                kind
+            }
+            c => {
+                return Err(ParseError {
+                    kind: ParseErrorKind::UnexpectedCharacter(c),
+                    position,
+                })
+            }
+        };
+        chars.next();
+        tokens.push(Token { kind, position });
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// length of the input, used as position for `UnexpectedEnd`
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&TokenKind> {
+        self.tokens.get(self.pos).map(|t| &t.kind)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.end, |t| t.position)
+    }
+
+    fn next(&mut self) -> Result<Token, ParseError> {
+        if self.pos >= self.tokens.len() {
+            return Err(ParseError {
+                kind: ParseErrorKind::UnexpectedEnd,
+                position: self.end,
+            });
+        }
+        self.pos += 1;
+        Ok(self.tokens[self.pos - 1].clone())
+    }
+
+    fn expect(&mut self, kind: TokenKind) -> Result<(), ParseError> {
+        let position = self.position();
+        let token = self.next()?;
+        if token.kind != kind {
+            return Err(ParseError {
+                kind: ParseErrorKind::UnexpectedToken,
+                position,
+            });
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self) -> Result<Operation, ParseError> {
+        let mut lhs = self.concat()?;
+
+        loop {
+            let comparison = match self.peek() {
+                Some(TokenKind::Equals) => Comparison::Equal,
+                Some(TokenKind::NotEqual) => Comparison::NotEqual,
+                Some(TokenKind::Less) => Comparison::Less,
+                Some(TokenKind::LessEqual) => Comparison::LessEqual,
+                Some(TokenKind::Greater) => Comparison::Greater,
+                Some(TokenKind::GreaterEqual) => Comparison::GreaterEqual,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.concat()?;
+            lhs = Operation::Compare(comparison, Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn concat(&mut self) -> Result<Operation, ParseError> {
+        let mut lhs = self.sum()?;
+
+        while let Some(TokenKind::Ampersand) = self.peek() {
+            self.pos += 1;
+            let rhs = self.sum()?;
+            lhs = Operation::Concat(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn sum(&mut self) -> Result<Operation, ParseError> {
+        let mut lhs = self.term()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Plus) => Operation::Add,
+                Some(TokenKind::Minus) => Operation::Sub,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.term()?;
+            lhs = op(Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn term(&mut self) -> Result<Operation, ParseError> {
+        let mut lhs = self.unary()?;
+
+        loop {
+            let op = match self.peek() {
+                Some(TokenKind::Star) => Operation::Mul,
+                Some(TokenKind::Slash) => Operation::Div,
+                Some(TokenKind::Percent) => Operation::Mod,
+                _ => return Ok(lhs),
+            };
+            self.pos += 1;
+            let rhs = self.unary()?;
+            lhs = op(Box::new(lhs), Box::new(rhs));
+        }
+    }
+
+    fn unary(&mut self) -> Result<Operation, ParseError> {
+        match self.peek() {
+            Some(TokenKind::Plus) => {
+                self.pos += 1;
+                self.unary()
+            }
+            Some(TokenKind::Minus) => {
+                self.pos += 1;
+                // fold negative literals directly into the value
+                match self.unary()? {
+                    Operation::Value(v) => Ok(Operation::Value(-v)),
+                    op => Ok(Operation::Neg(Box::new(op))),
+                }
+            }
+            _ => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> Result<Operation, ParseError> {
+        let base = self.primary()?;
+
+        if let Some(TokenKind::Caret) = self.peek() {
+            self.pos += 1;
+            // right associative, and the exponent may be negated, e.g. `2^-1`
+            let exponent = self.unary()?;
+            return Ok(Operation::Pow(Box::new(base), Box::new(exponent)));
+        }
+
+        Ok(base)
+    }
+
+    /// comma separated arguments of a function call, after the opening parenthesis
+    fn arguments(&mut self) -> Result<Vec<Operation>, ParseError> {
+        let mut args = vec![];
+
+        if let Some(TokenKind::RParen) = self.peek() {
+            self.pos += 1;
+            return Ok(args);
+        }
+
+        loop {
+            args.push(self.expr()?);
+
+            let position = self.position();
+            match self.next()?.kind {
+                TokenKind::Comma => (),
+                TokenKind::RParen => return Ok(args),
+                _ => {
+                    return Err(ParseError {
+                        kind: ParseErrorKind::UnexpectedToken,
+                        position,
+                    })
+                }
+            }
+        }
+    }
+
+    fn primary(&mut self) -> Result<Operation, ParseError> {
+        let token = self.next()?;
+
+        match token.kind {
+            TokenKind::Number(v) => Ok(Operation::Value(v)),
+            TokenKind::Str(s) => Ok(Operation::Text(s)),
+            TokenKind::Error(e) => Ok(Operation::Error(e)),
+            TokenKind::LParen => {
+                let op = self.expr()?;
+                self.expect(TokenKind::RParen)?;
+                Ok(op)
+            }
+            TokenKind::Ident(name) => {
+                if let Some(TokenKind::LParen) = self.peek() {
+                    self.pos += 1;
+                    let args = self.arguments()?;
+                    Ok(function(name, args))
+                } else if name.eq_ignore_ascii_case("true") {
+                    Ok(Operation::Bool(true))
+                } else if name.eq_ignore_ascii_case("false") {
+                    Ok(Operation::Bool(false))
+                } else {
+                    let from = cell(name, token.position)?;
+
+                    if let Some(TokenKind::Colon) = self.peek() {
+                        self.pos += 1;
+                        let token = self.next()?;
+                        match token.kind {
+                            TokenKind::Ident(name) => {
+                                Ok(Operation::Range((from, cell(name, token.position)?)))
+                            }
+                            _ => Err(ParseError {
+                                kind: ParseErrorKind::UnexpectedToken,
+                                position: token.position,
+                            }),
+                        }
+                    } else {
+                        Ok(Operation::Cell(from))
+                    }
+                }
+            }
+            TokenKind::RParen
+            | TokenKind::Comma
+            | TokenKind::Colon
+            | TokenKind::Plus
+            | TokenKind::Minus
+            | TokenKind::Star
+            | TokenKind::Slash
+            | TokenKind::Percent
+            | TokenKind::Caret
+            | TokenKind::Ampersand
+            | TokenKind::Equals
+            | TokenKind::NotEqual
+            | TokenKind::Less
+            | TokenKind::LessEqual
+            | TokenKind::Greater
+            | TokenKind::GreaterEqual => Err(ParseError {
+                kind: ParseErrorKind::UnexpectedToken,
+                position: token.position,
+            }),
+        }
+    }
+}
+
+fn cell(name: String, position: usize) -> Result<Coord, ParseError> {
+    match name.parse::<Address>() {
+        Ok(address) => Ok(address.coord()),
+        Err(_) => Err(ParseError {
+            kind: ParseErrorKind::InvalidCell(name),
+            position,
+        }),
+    }
+}
+
+/// Whether `name` is part of the formula language instead of a `function::Function`,
+/// e.g. `SUM` or `IF`
+pub fn is_reserved(name: &str) -> bool {
+    !matches!(function(name.to_string(), vec![]), Operation::Call(..))
+}
+
+fn function(name: String, args: Vec<Operation>) -> Operation {
+    match name.to_ascii_lowercase().as_str() {
+        "sum" => Operation::Aggregate(Aggregate::Sum, args),
+        "average" => Operation::Aggregate(Aggregate::Average, args),
+        "min" => Operation::Aggregate(Aggregate::Min, args),
+        "max" => Operation::Aggregate(Aggregate::Max, args),
+        "count" => Operation::Aggregate(Aggregate::Count, args),
+        "if" => Operation::Logical(Logical::If, args),
+        "and" => Operation::Logical(Logical::And, args),
+        "or" => Operation::Logical(Logical::Or, args),
+        "xor" => Operation::Logical(Logical::Xor, args),
+        "not" => Operation::Logical(Logical::Not, args),
+        "iferror" => Operation::Logical(Logical::IfError, args),
+        "ifs" => Operation::Logical(Logical::Ifs, args),
+        "switch" => Operation::Logical(Logical::Switch, args),
+        "vlookup" => Operation::Lookup(Lookup::VLookup, args),
+        "hlookup" => Operation::Lookup(Lookup::HLookup, args),
+        "index" => Operation::Lookup(Lookup::Index, args),
+        "match" => Operation::Lookup(Lookup::Match, args),
+        "xlookup" => Operation::Lookup(Lookup::XLookup, args),
+        _ => Operation::Call(name.to_ascii_uppercase(), args),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn err(input: &str) -> ParseError {
+        parse(input).err().unwrap()
+    }
+
+    fn cell(coord: Coord) -> Box<Operation> {
+        Box::new(Operation::Cell(coord))
+    }
+
+    #[test]
+    fn parse_values() {
+        assert_eq!(parse("=3.5"), Ok(Operation::Value(3.5)));
+        assert_eq!(parse("1e-3"), Ok(Operation::Value(1e-3)));
+        assert_eq!(parse("-(2)"), Ok(Operation::Value(-2.)));
+        assert_eq!(parse("= A1"), Ok(Operation::Cell((1, 1))));
+        assert_eq!(parse("=$b$12"), Ok(Operation::Cell((2, 12))));
+    }
+
+    #[test]
+    fn parse_errors_values() {
+        assert_eq!(parse("=#DIV/0!"), Ok(Operation::Error(CellError::Div0)));
+        assert_eq!(
+            parse("=#REF! + 1"),
+            Ok(Operation::Add(
+                Box::new(Operation::Error(CellError::Ref)),
+                Box::new(Operation::Value(1.))
+            ))
+        );
+        assert_eq!(parse("=#NUM!"), Ok(Operation::Error(CellError::Num)));
+        assert_eq!(
+            parse("=foo(A1)"),
+            Ok(Operation::Call(
+                "FOO".to_string(),
+                vec![Operation::Cell((1, 1))]
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_text_and_bool() {
+        assert_eq!(parse("=\"abc\""), Ok(Operation::Text("abc".to_string())));
+        assert_eq!(
+            parse("=\"say \"\"hi\"\"\""),
+            Ok(Operation::Text("say \"hi\"".to_string()))
+        );
+        assert_eq!(parse("=true"), Ok(Operation::Bool(true)));
+        assert_eq!(parse("=FALSE"), Ok(Operation::Bool(false)));
+        assert_eq!(
+            err("=\"abc"),
+            ParseError {
+                kind: ParseErrorKind::UnterminatedString,
+                position: 1
+            }
+        );
+    }
+
+    #[test]
+    fn parse_raw_input() {
+        assert_eq!(parse_input(" 3.5 "), Ok(Operation::Value(3.5)));
+        assert_eq!(parse_input("True"), Ok(Operation::Bool(true)));
+        assert_eq!(
+            parse_input("A1 + 1"),
+            Ok(Operation::Text("A1 + 1".to_string()))
+        );
+        assert_eq!(
+            parse_input("=A1 + 1"),
+            Ok(Operation::Add(cell((1, 1)), Box::new(Operation::Value(1.))))
+        );
+        assert!(parse_input("=A1 +").is_err());
+        for text in &["NaN", "inf", "-Infinity", "1e999"] {
+            assert_eq!(parse_input(text), Ok(Operation::Text(text.to_string())));
+            assert_eq!(to_input(&parse_input(text).unwrap()), *text);
+        }
+    }
+
+    #[test]
+    fn parse_operations() {
+        assert_eq!(
+            parse("=A1 + 3"),
+            Ok(Operation::Add(cell((1, 1)), Box::new(Operation::Value(3.))))
+        );
+        assert_eq!(
+            parse("=SIN((B2))"),
+            Ok(Operation::Call(
+                "SIN".to_string(),
+                vec![Operation::Cell((2, 2))]
+            ))
+        );
+        assert_eq!(
+            parse("=A1 + round(B2 + B3, 2)"),
+            Ok(Operation::Add(
+                cell((1, 1)),
+                Box::new(Operation::Call(
+                    "ROUND".to_string(),
+                    vec![
+                        Operation::Add(cell((2, 2)), cell((2, 3))),
+                        Operation::Value(2.)
+                    ]
+                ))
+            ))
+        );
+        assert_eq!(
+            parse("=pi()"),
+            Ok(Operation::Call("PI".to_string(), vec![]))
+        );
+    }
+
+    #[test]
+    fn parse_ranges_and_aggregates() {
+        assert_eq!(parse("=A1:B3"), Ok(Operation::Range(((1, 1), (2, 3)))));
+        assert_eq!(
+            parse("=sum(A1:B3, 2, C1)"),
+            Ok(Operation::Aggregate(
+                Aggregate::Sum,
+                vec![
+                    Operation::Range(((1, 1), (2, 3))),
+                    Operation::Value(2.),
+                    Operation::Cell((3, 1))
+                ]
+            ))
+        );
+        assert_eq!(
+            parse("=AVERAGE(A1:A3) + COUNT(B1)"),
+            Ok(Operation::Add(
+                Box::new(Operation::Aggregate(
+                    Aggregate::Average,
+                    vec![Operation::Range(((1, 1), (1, 3)))]
+                )),
+                Box::new(Operation::Aggregate(
+                    Aggregate::Count,
+                    vec![Operation::Cell((2, 1))]
+                ))
+            ))
+        );
+        // the number of arguments is checked on evaluation
+        assert_eq!(
+            parse("=SUM()"),
+            Ok(Operation::Aggregate(Aggregate::Sum, vec![]))
+        );
+        assert_eq!(
+            parse("=sin(A1, A2)"),
+            Ok(Operation::Call(
+                "SIN".to_string(),
+                vec![Operation::Cell((1, 1)), Operation::Cell((1, 2))]
+            ))
+        );
+        assert_eq!(
+            err("=SUM(A1:3)"),
+            ParseError {
+                kind: ParseErrorKind::UnexpectedToken,
+                position: 8
+            }
+        );
+        assert_eq!(
+            err("=SUM(A1 A2)"),
+            ParseError {
+                kind: ParseErrorKind::UnexpectedToken,
+                position: 8
+            }
+        );
+    }
+
+    #[test]
+    fn parse_left_associative() {
+        assert_eq!(
+            parse("=A1 + A2 + A3"),
+            Ok(Operation::Add(
+                Box::new(Operation::Add(cell((1, 1)), cell((1, 2)))),
+                cell((1, 3))
+            ))
+        );
+        assert_eq!(
+            parse("=A1 + (A2 + A3)"),
+            Ok(Operation::Add(
+                cell((1, 1)),
+                Box::new(Operation::Add(cell((1, 2)), cell((1, 3))))
+            ))
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(
+            err("=A1 + ~"),
+            ParseError {
+                kind: ParseErrorKind::UnexpectedCharacter('~'),
+                position: 6
+            }
+        );
+        assert_eq!(
+            err("=A1 +"),
+            ParseError {
+                kind: ParseErrorKind::UnexpectedEnd,
+                position: 5
+            }
+        );
+        assert_eq!(
+            err("=(A1"),
+            ParseError {
+                kind: ParseErrorKind::UnexpectedEnd,
+                position: 4
+            }
+        );
+        assert_eq!(
+            err("=A1 A2"),
+            ParseError {
+                kind: ParseErrorKind::UnexpectedToken,
+                position: 4
+            }
+        );
+        assert_eq!(
+            err("=cos(A1"),
+            ParseError {
+                kind: ParseErrorKind::UnexpectedEnd,
+                position: 7
+            }
+        );
+        assert_eq!(
+            err("=#FOO!"),
+            ParseError {
+                kind: ParseErrorKind::UnexpectedCharacter('#'),
+                position: 1
+            }
+        );
+        assert_eq!(
+            err("=A0"),
+            ParseError {
+                kind: ParseErrorKind::InvalidCell("A0".to_string()),
+                position: 1
+            }
+        );
+        assert_eq!(
+            err("=A$$1"),
+            ParseError {
+                kind: ParseErrorKind::InvalidCell("A$$1".to_string()),
+                position: 1
+            }
+        );
+        assert_eq!(
+            err("=1..2"),
+            ParseError {
+                kind: ParseErrorKind::InvalidNumber,
+                position: 1
+            }
+        );
+        assert_eq!(
+            err("=1e999 + 1"),
+            ParseError {
+                kind: ParseErrorKind::InvalidNumber,
+                position: 1
+            }
+        );
+    }
+
+    #[test]
+    fn parse_precedence() {
+        let val = |v| Box::new(Operation::Value(v));
+        assert_eq!(
+            parse("=1 + 2 * 3"),
+            Ok(Operation::Add(
+                val(1.),
+                Box::new(Operation::Mul(val(2.), val(3.)))
+            ))
+        );
+        assert_eq!(
+            parse("=(1 + 2) * 3"),
+            Ok(Operation::Mul(
+                Box::new(Operation::Add(val(1.), val(2.))),
+                val(3.)
+            ))
+        );
+        assert_eq!(
+            parse("=1 - 2 - 3"),
+            Ok(Operation::Sub(
+                Box::new(Operation::Sub(val(1.), val(2.))),
+                val(3.)
+            ))
+        );
+        assert_eq!(
+            parse("=A1 / 2 % 3"),
+            Ok(Operation::Mod(
+                Box::new(Operation::Div(cell((1, 1)), val(2.))),
+                val(3.)
+            ))
+        );
+        assert_eq!(
+            parse("=2^3^2"),
+            Ok(Operation::Pow(
+                val(2.),
+                Box::new(Operation::Pow(val(3.), val(2.)))
+            ))
+        );
+        assert_eq!(
+            parse("=-2^2"),
+            Ok(Operation::Neg(Box::new(Operation::Pow(val(2.), val(2.)))))
+        );
+        assert_eq!(parse("=2^-1"), Ok(Operation::Pow(val(2.), val(-1.))));
+        assert_eq!(parse("=-A1"), Ok(Operation::Neg(cell((1, 1)))));
+    }
+
+    #[test]
+    fn parse_comparisons() {
+        let val = |v| Box::new(Operation::Value(v));
+        let compare = |c, o1, o2| Operation::Compare(c, o1, o2);
+        assert_eq!(
+            parse("=A1 <> 1 + 2"),
+            Ok(compare(
+                Comparison::NotEqual,
+                cell((1, 1)),
+                Box::new(Operation::Add(val(1.), val(2.)))
+            ))
+        );
+        assert_eq!(
+            parse("=1<=2>3"),
+            Ok(compare(
+                Comparison::Greater,
+                Box::new(compare(Comparison::LessEqual, val(1.), val(2.))),
+                val(3.)
+            ))
+        );
+        assert_eq!(
+            parse("=A1=-1"),
+            Ok(compare(Comparison::Equal, cell((1, 1)), val(-1.)))
+        );
+        assert_eq!(
+            parse("=If(A1 < 0, 0, A1)"),
+            Ok(Operation::Logical(
+                Logical::If,
+                vec![
+                    compare(Comparison::Less, cell((1, 1)), val(0.)),
+                    Operation::Value(0.),
+                    Operation::Cell((1, 1))
+                ]
+            ))
+        );
+        assert_eq!(
+            parse("=1 < A1 & 2"),
+            Ok(compare(
+                Comparison::Less,
+                val(1.),
+                Box::new(Operation::Concat(cell((1, 1)), val(2.)))
+            ))
+        );
+        assert_eq!(
+            err("=1 < > 2"),
+            ParseError {
+                kind: ParseErrorKind::UnexpectedToken,
+                position: 5
+            }
+        );
+    }
+
+    #[test]
+    fn format_round_trip() {
+        for (input, formatted) in &[
+            ("=1 + 2 * 3", "1+2*3"),
+            ("=(1 + 2) * 3", "(1+2)*3"),
+            ("=1 - (2 - 3)", "1-(2-3)"),
+            ("=(1 - 2) - 3", "1-2-3"),
+            ("=(2^3)^2", "(2^3)^2"),
+            ("=2^3^2", "2^3^2"),
+            ("=(-2)^2", "(-2)^2"),
+            ("=-2^2", "-2^2"),
+            ("=2^-A1", "2^-A1"),
+            ("=-(A1 + $B$2)", "-(A1+B2)"),
+            ("=A1 * -B2 % 3", "A1*-B2%3"),
+            ("=sum(a1:b3, 2) / sin(c1)", "SUM(A1:B3, 2)/SIN(C1)"),
+            ("=Log(8, 2) + pi() + foo()", "LOG(8, 2)+PI()+FOO()"),
+            ("=\"say \"\"hi\"\"\"", "\"say \"\"hi\"\"\""),
+            ("=#DIV/0!", "#DIV/0!"),
+            ("=a1 + 1 >= 2 * b1", "A1+1>=2*B1"),
+            ("=(1 < 2) = TRUE", "1<2=TRUE"),
+            ("=1 <> (2 > 3)", "1<>(2>3)"),
+            ("=-(A1 = 1)", "-(A1=1)"),
+            (
+                "=if(and(A1, B1:B3), #N/A, 1)",
+                "IF(AND(A1, B1:B3), #N/A, 1)",
+            ),
+        ] {
+            let op = parse(input).unwrap();
+            assert_eq!(op.to_string(), *formatted);
+            assert_eq!(parse(formatted), Ok(op));
+        }
+    }
+
+    #[test]
+    fn raw_input_round_trip() {
+        for input in &["3.5", "TRUE", "hello", "=A1+1", "=\"12\"", "=\"=A1\""] {
+            assert_eq!(to_input(&parse_input(input).unwrap()), *input);
+        }
+        assert_eq!(to_input(&Operation::Text("12".to_string())), "=\"12\"");
+    }
+}
@@ -1,98 +1,596 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
 
-use crate::cell::{self, Cell};
+use crate::address::Address;
+use crate::cell::{self, Cell, CellId, CellRange, CellValue, Cells};
+use crate::file::{self, FileError};
+use crate::format::Format;
+use crate::function::{Function, Functions};
+use crate::operation::{self, ByCoord, ById, Resolver};
+use crate::parser;
 
-type Coord = (u32, u32);
+/// `(column, row)`, both starting at 1
+pub type Coord = (u32, u32);
 
+#[derive(Debug, PartialEq)]
+pub enum TableError {
+    /// The operation would make the cell depend on itself.
+    /// Contains the cells of the cycle, starting and ending with the inserted cell,
+    /// where each cell references the next one
+    Cycle(Vec<Coord>),
+    /// The ranges of the operation span more than `MAX_RANGE_SIZE` cells together,
+    /// contains their number
+    RangesTooLarge(u64),
+    /// Inserting rows would move cells past the last row
+    RowOverflow,
+    /// The function can't be registered, since the name is part of the formula language,
+    /// see `parser::is_reserved`
+    ReservedFunction(String),
+}
+
+impl fmt::Display for TableError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TableError::Cycle(cycle) => {
+                write!(f, "circular reference: ")?;
+                let cells: Vec<String> =
+                    cycle.iter().map(|c| Address::new(*c).to_string()).collect();
+                write!(f, "{}", cells.join(" -> "))
+            }
+            TableError::RangesTooLarge(size) => write!(
+                f,
+                "ranges span {} cells, more than {}",
+                size, MAX_RANGE_SIZE
+            ),
+            TableError::RowOverflow => write!(f, "cells would be moved past the last row"),
+            TableError::ReservedFunction(name) => {
+                write!(f, "{} is built in and can't be replaced", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TableError {}
+
+/// Called after every change with the cells whose value changed, in the order in which they
+/// were recalculated. Removed cells come first
+pub type Observer = Arc<dyn Fn(&[Coord]) + Send + Sync>;
+
+#[derive(Clone)]
 pub struct Table {
-    table: HashMap<Coord, Cell>,
+    /// ids of all cells with content or dependents
+    ids: HashMap<Coord, CellId>,
+    cells: Cells,
+    /// the operations as inserted, i.e. the formulas of all non-empty cells
+    operations: HashMap<Coord, Operation>,
     references: HashMap<Coord, Vec<Coord>>,
     referenced_by: HashMap<Coord, Vec<Coord>>,
+    /// display formats of cells, independent of their content. Cells without entry are `Format::General`
+    formats: HashMap<Coord, Format>,
+    /// widths of columns in characters, for columns which don't use the default width
+    column_widths: HashMap<u32, u16>,
+    history: History,
+    observer: Option<Observer>,
+    functions: Functions,
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Table {
     pub fn new() -> Table {
         Table {
-            table: HashMap::new(),
+            ids: HashMap::new(),
+            cells: Cells::new(),
+            operations: HashMap::new(),
             references: HashMap::new(),
             referenced_by: HashMap::new(),
+            formats: HashMap::new(),
+            column_widths: HashMap::new(),
+            history: History::new(),
+            observer: None,
+            functions: Functions::new(),
         }
     }
 
-    pub fn insert(&mut self, coord: Coord, operation: Operation) {
-        let cell_op: cell::Operation;
+    /// Read a table from the file at `path`, see `file` for the format
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Table, FileError> {
+        file::read(BufReader::new(File::open(path)?))
+    }
 
-        // remove potential old references, since insert always overwrites a (potentially empty) cell
-        self.update_references_remove(&coord);
+    /// Write the table to the file at `path`, see `file` for the format
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), FileError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        file::write(self, &mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
 
-        match operation {
-            Operation::None(o) => {
-                let co = self.match_operation_value(o, &coord);
-                cell_op = cell::Operation::None(co);
-            }
-            Operation::Add(o1, o2) => {
-                let co1 = self.match_operation_value(o1, &coord);
-                let co2 = self.match_operation_value(o2, &coord);
+    /// Set the operation of the cell at `coord` and recalculate all cells depending on it
+    /// If `operation` would close a circular reference the table is left unchanged
+    pub fn insert(&mut self, coord: Coord, operation: Operation) -> Result<(), TableError> {
+        let old = self.replace(coord, operation)?;
+        self.history.record(coord, old);
+        Ok(())
+    }
+
+    /// Remove the cell at `coord` and recalculate all cells depending on it,
+    /// which see it as empty from now on
+    pub fn remove(&mut self, coord: &Coord) {
+        if let Some(old) = self.take(coord) {
+            self.history.record(*coord, Some(old));
+        }
+    }
 
-                cell_op = cell::Operation::Add(co1, co2)
+    /// Remove all cells in the rectangle spanned by the corners `from` and `to`,
+    /// see `remove`. Can be undone in one step
+    pub fn clear_range(&mut self, from: &Coord, to: &Coord) {
+        let (x1, x2) = (from.0.min(to.0), from.0.max(to.0));
+        let (y1, y2) = (from.1.min(to.1), from.1.max(to.1));
+
+        let in_range: Vec<Coord> = self
+            .ids
+            .keys()
+            .filter(|(x, y)| x1 <= *x && *x <= x2 && y1 <= *y && *y <= y2)
+            .copied()
+            .collect();
+
+        self.begin_transaction();
+        let mut cleared = vec![];
+        for c in in_range {
+            let old = self.clear(&c);
+            // cells which are still there are referenced, the others are gone
+            if old.is_some() || self.ids.contains_key(&c) {
+                cleared.push(c);
             }
-            Operation::Sine(o) => {
-                let co = self.match_operation_value(o, &coord);
-                cell_op = cell::Operation::Sine(co);
+            if let Some(old) = old {
+                self.history.record(c, Some(old));
             }
         }
+        self.end_transaction();
+
+        self.update_cells(&cleared);
+    }
 
-        // self.table.insert(coord, c);
-        self.table
-            .entry(coord)
-            .or_insert_with(Cell::new)
-            .set_operation(cell_op);
+    /// Set the operations of the block of cells whose top left corner is `at`, given row by row.
+    /// `None` removes the cell. Can be undone in one step. If an operation would close a circular
+    /// reference the table is left unchanged
+    pub fn paste(
+        &mut self,
+        at: Coord,
+        rows: Vec<Vec<Option<Operation>>>,
+    ) -> Result<(), TableError> {
+        let mut changes = vec![];
+        for (y, row) in rows.into_iter().enumerate() {
+            for (x, operation) in row.into_iter().enumerate() {
+                let coord = (at.0 + x as u32, at.1 + y as u32);
+                let old = match operation {
+                    Some(op) => match self.replace(coord, op) {
+                        Ok(old) => old,
+                        Err(err) => {
+                            self.apply(changes);
+                            return Err(err);
+                        }
+                    },
+                    None => self.take(&coord),
+                };
+                changes.push(Change {
+                    coord,
+                    operation: old,
+                });
+            }
+        }
 
-        self.update_cell(&coord);
+        self.record_all(changes);
+        Ok(())
     }
 
-    pub fn iter(&self) -> std::collections::hash_map::Iter<Coord, Cell> {
-        self.table.iter()
+    /// Insert `count` empty rows before `row`, moving the cells below down and adjusting the
+    /// references to them. Ranges across `row` grow. Can be undone in one step, except for the
+    /// moved formats which, as with `set_format`, aren't part of the history.
+    /// If a range would grow too large the table is left unchanged
+    pub fn insert_rows(&mut self, row: u32, count: u32) -> Result<(), TableError> {
+        let mut shift = RowShift { row, count };
+
+        let last = self
+            .ids
+            .keys()
+            .chain(self.formats.keys())
+            .map(|c| c.1)
+            .max();
+        if last.is_some_and(|last| last >= row && last.checked_add(count).is_none()) {
+            return Err(TableError::RowOverflow);
+        }
+
+        let moved: Vec<(Coord, Coord, Operation)> = self
+            .operations
+            .iter()
+            .map(|(coord, op)| (*coord, shift.cell(coord), op.resolve(&mut shift)))
+            .filter(|(from, to, op)| from != to || *op != self.operations[from])
+            .collect();
+
+        // clearing all moved cells first keeps every intermediate state free of cycles,
+        // since each one is part of the final table
+        let mut changes = vec![];
+        for (from, _, _) in &moved {
+            changes.push(Change {
+                coord: *from,
+                operation: self.take(from),
+            });
+        }
+        for (_, to, op) in moved {
+            match self.replace(to, op) {
+                Ok(old) => changes.push(Change {
+                    coord: to,
+                    operation: old,
+                }),
+                Err(err) => {
+                    self.apply(changes);
+                    return Err(err);
+                }
+            }
+        }
+        self.record_all(changes);
+
+        self.formats = self
+            .formats
+            .drain()
+            .map(|(coord, format)| (shift.cell(&coord), format))
+            .collect();
+        Ok(())
     }
 
-    pub fn get_value_of_cell(&self, coord: &Coord) -> Option<f64> {
-        self.table.get(coord).map(|c| c.get_value())
+    /// Revert the last change, or transaction of changes, to the contents of cells.
+    /// Returns false if there is nothing to undo
+    pub fn undo(&mut self) -> bool {
+        match self.history.undo.pop_back() {
+            Some(changes) => {
+                let redo = self.apply(changes);
+                self.history.redo.push(redo);
+                true
+            }
+            None => false,
+        }
     }
 
-    fn update_cell(&mut self, to_update: &Coord) {
-        let mut updater = CellUpdater { to_update: vec![] };
+    /// Revert the last `undo`. Returns false if there is nothing to redo
+    pub fn redo(&mut self) -> bool {
+        match self.history.redo.pop() {
+            Some(changes) => {
+                let undo = self.apply(changes);
+                self.history.push(undo);
+                true
+            }
+            None => false,
+        }
+    }
 
-        updater.gather_cells_to_update(to_update, &self.referenced_by);
-        updater.remove_duplicates();
+    /// Group all changes until the matching `end_transaction` into a single undo step.
+    /// Transactions can be nested, only the outermost one counts
+    pub fn begin_transaction(&mut self) {
+        self.history.depth += 1;
+    }
 
-        // important to go from front to back
-        for i in &updater.to_update {
-            println!("{:?}", *i);
-            if let Some(c) = self.table.get(i) {
-                c.update();
+    pub fn end_transaction(&mut self) {
+        self.history.depth = self.history.depth.saturating_sub(1);
+        if self.history.depth == 0 {
+            let changes = std::mem::take(&mut self.history.transaction);
+            if !changes.is_empty() {
+                self.history.push(changes);
             }
         }
     }
 
-    fn match_operation_value(
+    /// Report the cells whose value changed after each change to `observer`, see `Observer`.
+    /// Clones of the table share the observer
+    pub fn set_observer(&mut self, observer: Option<Observer>) {
+        self.observer = observer;
+    }
+
+    /// Make `function` callable from formulas, replacing any function with the same name,
+    /// including built-in ones like `ABS`. Cells which already call a function of that name are
+    /// recalculated. Names which are part of the formula language, like `SUM` or `IF`, can't be
+    /// replaced, see `parser::is_reserved`
+    pub fn register_function(&mut self, function: Arc<dyn Function>) -> Result<(), TableError> {
+        let name = function.name().to_string();
+        if parser::is_reserved(&name) {
+            return Err(TableError::ReservedFunction(name));
+        }
+        self.functions.register(function);
+
+        let callers: Vec<(Coord, Operation)> = self
+            .operations
+            .iter()
+            .filter(|(_, op)| op.calls(&name))
+            .map(|(coord, op)| (*coord, op.clone()))
+            .collect();
+        for (coord, op) in &callers {
+            // the references stay the same, only the called function changes
+            let cell_op = self.resolve_operation(op);
+            let id = self.ids[coord];
+            self.cells.get_mut(id).unwrap().set_operation(cell_op);
+        }
+
+        let changed: Vec<Coord> = callers.into_iter().map(|(coord, _)| coord).collect();
+        self.update_cells(&changed);
+        Ok(())
+    }
+
+    /// Number of changes, or transactions, which can be undone at most.
+    /// Older changes are forgotten
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history.limit = limit;
+        self.history.truncate();
+    }
+
+    /// Forget all changes, so that they can't be undone
+    pub fn clear_history(&mut self) {
+        self.history.undo.clear();
+        self.history.redo.clear();
+    }
+
+    /// Record `changes`, which already happened in this order, as one undo step
+    fn record_all(&mut self, changes: Vec<Change>) {
+        self.begin_transaction();
+        for Change { coord, operation } in changes {
+            self.history.record(coord, operation);
+        }
+        self.end_transaction();
+    }
+
+    /// Apply the `changes` in reverse order and return the changes which revert them
+    fn apply(&mut self, changes: Vec<Change>) -> Vec<Change> {
+        let mut reverted = Vec::with_capacity(changes.len());
+
+        for Change { coord, operation } in changes.into_iter().rev() {
+            let old = match operation {
+                // the table was in this state before, so there can't be a cycle
+                Some(op) => self
+                    .replace(coord, op)
+                    .expect("restored operation closes a circular reference"),
+                None => self.take(&coord),
+            };
+            reverted.push(Change {
+                coord,
+                operation: old,
+            });
+        }
+
+        reverted
+    }
+
+    /// `insert` without recording the change. Returns the previous operation
+    fn replace(
         &mut self,
-        op: OperationValue,
-        new_cell: &Coord,
-    ) -> cell::OperationValue {
-        match op {
-            OperationValue::Value(val) => cell::OperationValue::Value(val),
-            OperationValue::Cell(coord) => {
-                // ensure cell is valid. Default value is 0.0
-                if !self.table.contains_key(&coord) {
-                    self.insert(coord, Operation::None(OperationValue::Value(0.0)));
+        coord: Coord,
+        operation: Operation,
+    ) -> Result<Option<Operation>, TableError> {
+        let size = operation.ranges_size();
+        if size > MAX_RANGE_SIZE {
+            return Err(TableError::RangesTooLarge(size));
+        }
+        let references = operation.references();
+        if let Some(cycle) = self.find_cycle(&coord, &references) {
+            return Err(TableError::Cycle(cycle));
+        }
+
+        // remove potential old references, since insert always overwrites a (potentially empty) cell
+        self.update_references_remove(&coord);
+        self.update_references_insert(&coord, references);
+
+        let cell_op = self.resolve_operation(&operation);
+        let id = self.cell_id(coord);
+        self.cells.get_mut(id).unwrap().set_operation(cell_op);
+        let old = self.operations.insert(coord, operation);
+
+        self.update_cells(&[coord]);
+
+        Ok(old)
+    }
+
+    /// `remove` without recording the change. Returns the previous operation
+    fn take(&mut self, coord: &Coord) -> Option<Operation> {
+        let old = self.clear(coord);
+        // cells which are still there are referenced, the others are gone
+        if old.is_some() || self.ids.contains_key(coord) {
+            self.update_cells(&[*coord]);
+        }
+        old
+    }
+
+    /// All cells with content or dependents, in arbitrary order
+    pub fn iter(&self) -> impl Iterator<Item = (&Coord, &Cell)> {
+        self.ids
+            .iter()
+            .filter_map(move |(coord, id)| self.cells.get(*id).map(|cell| (coord, cell)))
+    }
+
+    /// Value of the cell at `coord`. Empty cells have value `CellValue::Unity`
+    pub fn get_value_of_cell(&self, coord: &Coord) -> Option<CellValue> {
+        self.get_cell(coord).map(|c| c.get_value())
+    }
+
+    fn get_cell(&self, coord: &Coord) -> Option<&Cell> {
+        self.ids.get(coord).and_then(|id| self.cells.get(*id))
+    }
+
+    /// Id of the cell at `coord`, which is created as empty cell if necessary
+    fn cell_id(&mut self, coord: Coord) -> CellId {
+        self.resolver().cell(&coord)
+    }
+
+    fn resolver(&mut self) -> IdResolver<'_> {
+        IdResolver {
+            ids: &mut self.ids,
+            cells: &mut self.cells,
+            functions: &self.functions,
+        }
+    }
+
+    /// Drop the cell at `coord`. Its id becomes invalid
+    fn drop_cell(&mut self, coord: &Coord) {
+        if let Some(id) = self.ids.remove(coord) {
+            self.cells.remove(id);
+        }
+    }
+
+    /// Operation of the cell at `coord` as it was inserted. `None` for empty cells
+    pub fn get_operation_of_cell(&self, coord: &Coord) -> Option<&Operation> {
+        self.operations.get(coord)
+    }
+
+    /// All non-empty cells with their operations as inserted, in arbitrary order
+    pub fn operations(&self) -> std::collections::hash_map::Iter<'_, Coord, Operation> {
+        self.operations.iter()
+    }
+
+    pub fn get_format(&self, coord: &Coord) -> Format {
+        self.formats.get(coord).copied().unwrap_or_default()
+    }
+
+    /// Set the display format of the cell at `coord`, whether it has content or not
+    pub fn set_format(&mut self, coord: Coord, format: Format) {
+        if format == Format::General {
+            self.formats.remove(&coord);
+        } else {
+            self.formats.insert(coord, format);
+        }
+    }
+
+    /// All cells with a format other than `Format::General`, in arbitrary order
+    pub fn formats(&self) -> std::collections::hash_map::Iter<'_, Coord, Format> {
+        self.formats.iter()
+    }
+
+    /// Width of column `col` in characters. `None` if the column has the default width
+    pub fn get_column_width(&self, col: u32) -> Option<u16> {
+        self.column_widths.get(&col).copied()
+    }
+
+    /// Set the width of column `col` in characters. `None` resets it to the default width
+    pub fn set_column_width(&mut self, col: u32, width: Option<u16>) {
+        match width {
+            Some(width) => self.column_widths.insert(col, width),
+            None => self.column_widths.remove(&col),
+        };
+    }
+
+    /// All columns which don't use the default width, in arbitrary order
+    pub fn column_widths(&self) -> std::collections::hash_map::Iter<'_, u32, u16> {
+        self.column_widths.iter()
+    }
+
+    /// Drop the operation and value of the cell at `coord` without recalculating its dependents.
+    /// Cells which are still referenced are kept as empty cells. Returns the previous operation
+    fn clear(&mut self, coord: &Coord) -> Option<Operation> {
+        let id = *self.ids.get(coord)?;
+
+        self.update_references_remove(coord);
+        let old = self.operations.remove(coord);
+
+        if self.referenced_by.get(coord).is_some_and(|r| !r.is_empty()) {
+            // dependents point to the value of this cell, so keep it as an empty cell
+            self.cells.get_mut(id).unwrap().clear_operation();
+        } else {
+            self.drop_cell(coord);
+            self.referenced_by.remove(coord);
+        }
+
+        old
+    }
+
+    /// Recalculate the cells `changed` and all cells depending on them, and report the cells whose
+    /// value changed to the observer. Cells in `changed` which don't exist anymore were removed
+    fn update_cells(&mut self, changed: &[Coord]) {
+        let updater = CellUpdater::new(changed, &self.referenced_by);
+
+        let mut changed_values = vec![];
+        let mut ids = vec![];
+        let mut coords = vec![];
+        // important to go from front to back. The cells of a level only depend on earlier levels
+        for level in updater.levels() {
+            ids.clear();
+            coords.clear();
+            for c in level {
+                match self.ids.get(c) {
+                    Some(id) => {
+                        ids.push(*id);
+                        coords.push(*c);
+                    }
+                    None => changed_values.push(*c),
                 }
+            }
+            self.cells
+                .update_independent(&ids, |i| changed_values.push(coords[i]));
+        }
 
-                self.update_references_insert(new_cell, &coord);
-                cell::OperationValue::Cell(self.table.get(&coord).unwrap().get_ptr_to_value())
+        if let Some(observer) = &self.observer {
+            if !changed_values.is_empty() {
+                observer(&changed_values);
             }
         }
     }
 
+    /// If `to_update` referencing `references` would close a cycle, return the cells of that cycle
+    fn find_cycle(&self, to_update: &Coord, references: &[Coord]) -> Option<Vec<Coord>> {
+        // a cycle exists iff one of the new references (transitively) depends on `to_update`.
+        // Walk the dependents of `to_update` and remember where we came from to rebuild the path
+        if references.is_empty() {
+            return None;
+        }
+
+        let references: HashSet<Coord> = references.iter().copied().collect();
+        let mut came_from: HashMap<Coord, Coord> = HashMap::new();
+        let mut stack = vec![*to_update];
+
+        let mut found = None;
+
+        if references.contains(to_update) {
+            found = Some(*to_update);
+        }
+
+        'search: while let Some(c) = stack.pop() {
+            if found.is_some() {
+                break;
+            }
+            for dependent in self.referenced_by.get(&c).into_iter().flatten() {
+                if *dependent == *to_update || came_from.contains_key(dependent) {
+                    continue;
+                }
+                came_from.insert(*dependent, c);
+
+                if references.contains(dependent) {
+                    found = Some(*dependent);
+                    break 'search;
+                }
+                stack.push(*dependent);
+            }
+        }
+
+        // walking back from `found` yields the cells in the order in which they reference each other
+        let mut cycle = vec![*to_update];
+        let mut c = found?;
+        while c != *to_update {
+            cycle.push(c);
+            c = came_from[&c];
+        }
+        cycle.push(*to_update);
+
+        Some(cycle)
+    }
+
+    /// translate the `Coord`s in `op` into the ids of the cells
+    fn resolve_operation(&mut self, op: &Operation) -> cell::Operation {
+        op.resolve(&mut self.resolver())
+    }
+
     /// remove cell `to_update` and update references
     fn update_references_remove(&mut self, to_update: &Coord) {
         // Let's say we have (where (n) is cell n)
@@ -105,116 +603,1671 @@ impl Table {
         //
         // so to remove e.g. (1), (1) contains nothing, then references(1) needs to be completely deleted
         // and everywhere where (1) appears in referenced_by (here for (2)) needs to remove the entry (1)
+        //
+        // cells which only exist because they were referenced are dropped once nobody references them
 
-        if let Some(refs) = self.references.get(to_update) {
-            for c in refs {
-                // self.referenced_by.remove(c);
-                if let Some(index) = self
-                    .referenced_by
-                    .get(c)
-                    .unwrap()
-                    .iter()
-                    .position(|coord| *coord == *c)
-                {
-                    self.referenced_by.get_mut(c).unwrap().swap_remove(index);
+        if let Some(refs) = self.references.remove(to_update) {
+            for c in &refs {
+                if let Some(referenced_by) = self.referenced_by.get_mut(c) {
+                    if let Some(index) = referenced_by.iter().position(|coord| *coord == *to_update)
+                    {
+                        referenced_by.swap_remove(index);
+                    }
+
+                    let cells = &self.cells;
+                    let is_empty = self
+                        .ids
+                        .get(c)
+                        .and_then(|id| cells.get(*id))
+                        .is_some_and(|c| c.is_empty());
+                    if referenced_by.is_empty() && is_empty {
+                        self.referenced_by.remove(c);
+                        if let Some(id) = self.ids.remove(c) {
+                            self.cells.remove(id);
+                        }
+                    }
                 }
             }
         }
+    }
 
-        self.references.remove(to_update);
+    /// insert cell `to_update` which references all cells in `references` and update the table
+    fn update_references_insert(&mut self, to_update: &Coord, references: Vec<Coord>) {
+        for c in &references {
+            self.referenced_by.entry(*c).or_default().push(*to_update);
+        }
+
+        self.references.insert(*to_update, references);
     }
+}
 
-    /// insert cell `to_update` which references cell `references` and update the table
-    fn update_references_insert(&mut self, to_update: &Coord, references: &Coord) {
-        self.references
-            .entry(*to_update)
-            .or_insert_with(Vec::new)
-            .push(*references);
+/// Operation of a cell as inserted, referencing other cells by their `Coord`
+pub type Operation = operation::Operation<ByCoord>;
 
-        for c in self.references.get(to_update).unwrap() {
-            self.referenced_by
-                .entry(*c)
-                .or_insert_with(Vec::new)
-                .push(*to_update);
+/// Moves references to cells at or below `row` down by `count` rows
+struct RowShift {
+    row: u32,
+    count: u32,
+}
+
+impl Resolver<ByCoord, ByCoord> for RowShift {
+    fn cell(&mut self, &(x, y): &Coord) -> Coord {
+        if y >= self.row {
+            (x, y + self.count)
+        } else {
+            (x, y)
         }
     }
+
+    fn range(&mut self, (from, to): &(Coord, Coord)) -> (Coord, Coord) {
+        (self.cell(from), self.cell(to))
+    }
+
+    fn function(&mut self, name: &String) -> String {
+        name.clone()
+    }
 }
 
-//redundant. cell::Operation and table::Operation have same content
-// only difference is, that OperationValue::Cell has different type.
-// ? maybe with generic types? .. hmm..
-pub enum Operation {
-    None(OperationValue),
-    Add(OperationValue, OperationValue),
-    Sine(OperationValue),
+/// Resolves references to the ids of the cells, which are created as empty cells if necessary,
+/// and function names to the functions of the table
+struct IdResolver<'a> {
+    ids: &'a mut HashMap<Coord, CellId>,
+    cells: &'a mut Cells,
+    functions: &'a Functions,
 }
 
-pub enum OperationValue {
-    Value(f64),
-    Cell(Coord),
+impl Resolver<ByCoord, ById> for IdResolver<'_> {
+    fn cell(&mut self, coord: &Coord) -> CellId {
+        let cells = &mut self.cells;
+        *self
+            .ids
+            .entry(*coord)
+            .or_insert_with(|| cells.insert(Cell::new()))
+    }
+
+    fn range(&mut self, (from, to): &(Coord, Coord)) -> CellRange {
+        CellRange {
+            ids: cells_in_range(from, to)
+                .iter()
+                .map(|c| self.cell(c))
+                .collect(),
+            width: from.0.abs_diff(to.0) as usize + 1,
+        }
+    }
+
+    fn function(&mut self, name: &String) -> Option<Arc<dyn Function>> {
+        self.functions.get(name).cloned()
+    }
 }
 
+impl Operation {
+    /// All cells this operation depends on, without duplicates
+    pub fn references(&self) -> Vec<Coord> {
+        let mut refs = vec![];
+        self.visit(&mut |op| match op {
+            Operation::Cell(coord) => refs.push(*coord),
+            Operation::Range((from, to)) => refs.extend(cells_in_range(from, to)),
+            _ => (),
+        });
+
+        let mut seen = HashSet::new();
+        refs.retain(|c| seen.insert(*c));
+        refs
+    }
+
+    /// Number of cells all ranges span together, counting overlapping cells repeatedly
+    fn ranges_size(&self) -> u64 {
+        let mut size = 0u64;
+        self.visit(&mut |op| {
+            if let Operation::Range((from, to)) = op {
+                size = size.saturating_add(range_size(from, to));
+            }
+        });
+        size
+    }
+
+    /// Whether this operation calls the function `name`, ignoring case
+    pub fn calls(&self, name: &str) -> bool {
+        let mut found = false;
+        self.visit(&mut |op| {
+            if let Operation::Call(n, _) = op {
+                found |= n.eq_ignore_ascii_case(name);
+            }
+        });
+        found
+    }
+
+    /// Binding strength when written as formula, higher binds stronger
+    fn precedence(&self) -> u8 {
+        match self {
+            Operation::Compare(..) => 0,
+            Operation::Concat(..) => 1,
+            Operation::Add(..) | Operation::Sub(..) => 2,
+            Operation::Mul(..) | Operation::Div(..) | Operation::Mod(..) => 3,
+            Operation::Neg(_) => 4,
+            // `-2^2` is `-(2^2)`, so negative numbers behave like a negation
+            Operation::Value(v) if v.is_sign_negative() => 4,
+            Operation::Pow(..) => 5,
+            _ => 6,
+        }
+    }
+
+    /// write `op`, wrapped in parentheses if it binds weaker than `min_precedence`
+    fn fmt_operand(f: &mut fmt::Formatter, op: &Operation, min_precedence: u8) -> fmt::Result {
+        if op.precedence() < min_precedence {
+            write!(f, "({})", op)
+        } else {
+            write!(f, "{}", op)
+        }
+    }
+
+    fn fmt_call(f: &mut fmt::Formatter, name: &str, args: &[Operation]) -> fmt::Result {
+        let args: Vec<String> = args.iter().map(|o| o.to_string()).collect();
+        write!(f, "{}({})", name, args.join(", "))
+    }
+
+    fn fmt_binary(
+        f: &mut fmt::Formatter,
+        o1: &Operation,
+        operator: &str,
+        o2: &Operation,
+        precedence: u8,
+    ) -> fmt::Result {
+        // all binary operators except `^` are left-associative
+        let (left, right) = if operator == "^" {
+            (precedence + 1, precedence - 1)
+        } else {
+            (precedence, precedence + 1)
+        };
+        Operation::fmt_operand(f, o1, left)?;
+        write!(f, "{}", operator)?;
+        Operation::fmt_operand(f, o2, right)
+    }
+}
+
+/// Formula syntax understood by the parser, without the leading `=`.
+/// Infinite numbers and NaN can't be written as literals, so they are written as `#NUM!`
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = self.precedence();
+        match self {
+            Operation::Value(v) if v.is_finite() => write!(f, "{}", v),
+            Operation::Value(_) => write!(f, "{}", cell::CellError::Num),
+            Operation::Text(s) => write!(f, "\"{}\"", s.replace('"', "\"\"")),
+            Operation::Bool(true) => write!(f, "TRUE"),
+            Operation::Bool(false) => write!(f, "FALSE"),
+            Operation::Error(e) => write!(f, "{}", e),
+            Operation::Cell(coord) => write!(f, "{}", Address::new(*coord)),
+            Operation::Add(o1, o2) => Operation::fmt_binary(f, o1, "+", o2, p),
+            Operation::Sub(o1, o2) => Operation::fmt_binary(f, o1, "-", o2, p),
+            Operation::Mul(o1, o2) => Operation::fmt_binary(f, o1, "*", o2, p),
+            Operation::Div(o1, o2) => Operation::fmt_binary(f, o1, "/", o2, p),
+            Operation::Mod(o1, o2) => Operation::fmt_binary(f, o1, "%", o2, p),
+            Operation::Pow(o1, o2) => Operation::fmt_binary(f, o1, "^", o2, p),
+            Operation::Concat(o1, o2) => Operation::fmt_binary(f, o1, "&", o2, p),
+            Operation::Compare(c, o1, o2) => Operation::fmt_binary(f, o1, &c.to_string(), o2, p),
+            Operation::Neg(o) => {
+                write!(f, "-")?;
+                Operation::fmt_operand(f, o, p)
+            }
+            Operation::Range((from, to)) => {
+                write!(f, "{}:{}", Address::new(*from), Address::new(*to))
+            }
+            Operation::Aggregate(a, args) => Operation::fmt_call(f, &a.to_string(), args),
+            Operation::Logical(l, args) => Operation::fmt_call(f, &l.to_string(), args),
+            Operation::Lookup(l, args) => Operation::fmt_call(f, &l.to_string(), args),
+            Operation::Call(name, args) => Operation::fmt_call(f, name, args),
+        }
+    }
+}
+
+/// Largest number of cells the ranges of a formula may span together, e.g. `A1:J10000` or
+/// `SUM(A1:A50000, C1:C50000)`. Every cell of a range is tracked as a dependency,
+/// so that larger ranges would take too much time and memory
+pub const MAX_RANGE_SIZE: u64 = 100_000;
+
+/// Number of cells in the rectangle spanned by the corners `from` and `to`
+pub fn range_size(from: &Coord, to: &Coord) -> u64 {
+    let width = u64::from(from.0.max(to.0) - from.0.min(to.0)) + 1;
+    let height = u64::from(from.1.max(to.1) - from.1.min(to.1)) + 1;
+    width * height
+}
+
+/// All cells of the rectangle spanned by the corners `from` and `to`, row by row
+pub fn cells_in_range(from: &Coord, to: &Coord) -> Vec<Coord> {
+    let (x1, x2) = (from.0.min(to.0), from.0.max(to.0));
+    let (y1, y2) = (from.1.min(to.1), from.1.max(to.1));
+
+    (y1..=y2)
+        .flat_map(|y| (x1..=x2).map(move |x| (x, y)))
+        .collect()
+}
+
+/// Default number of undo steps
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+/// Operation of a cell before it changed, `None` if the cell was empty
+#[derive(Clone)]
+struct Change {
+    coord: Coord,
+    operation: Option<Operation>,
+}
+
+/// Changes to the contents of cells, to undo and redo them.
+/// Each entry is a transaction of changes in the order in which they happened
+#[derive(Clone)]
+struct History {
+    undo: VecDeque<Vec<Change>>,
+    redo: Vec<Vec<Change>>,
+    /// changes of the open transaction
+    transaction: Vec<Change>,
+    /// number of open transactions
+    depth: usize,
+    limit: usize,
+}
+
+impl History {
+    fn new() -> History {
+        History {
+            undo: VecDeque::new(),
+            redo: vec![],
+            transaction: vec![],
+            depth: 0,
+            limit: DEFAULT_HISTORY_LIMIT,
+        }
+    }
+
+    /// Remember that the cell at `coord` had operation `old` before it was changed
+    fn record(&mut self, coord: Coord, old: Option<Operation>) {
+        // a new change makes the undone changes unreachable
+        self.redo.clear();
+
+        let change = Change {
+            coord,
+            operation: old,
+        };
+        if self.depth > 0 {
+            self.transaction.push(change);
+        } else {
+            self.push(vec![change]);
+        }
+    }
+
+    fn push(&mut self, changes: Vec<Change>) {
+        self.undo.push_back(changes);
+        self.truncate();
+    }
+
+    fn truncate(&mut self) {
+        while self.undo.len() > self.limit {
+            self.undo.pop_front();
+        }
+    }
+}
+
+/// Schedules the recalculation after a cell changed
 struct CellUpdater {
+    /// every cell depending (transitively) on the changed cell, in topological order,
+    /// i.e. each cell comes after all cells it references
     to_update: Vec<Coord>,
+    /// end of each level in `to_update`. The cells of a level are independent of each other
+    level_ends: Vec<usize>,
 }
 
 impl CellUpdater {
-    fn gather_cells_to_update(
-        &mut self,
-        cell_to_update: &Coord,
-        referenced_by: &HashMap<Coord, Vec<Coord>>,
-    ) {
-        self.to_update.push(*cell_to_update);
+    fn new(changed: &[Coord], referenced_by: &HashMap<Coord, Vec<Coord>>) -> CellUpdater {
+        let dirty = CellUpdater::mark_dirty(changed, referenced_by);
 
-        if let Some(refs) = referenced_by.get(cell_to_update) {
-            for c in refs {
-                self.gather_cells_to_update(c, referenced_by);
+        // number of not yet updated cells each dirty cell references.
+        // Cells outside of `dirty` are up to date and don't count
+        let mut pending: HashMap<Coord, usize> = dirty.iter().map(|c| (*c, 0)).collect();
+        for c in &dirty {
+            for dependent in referenced_by.get(c).into_iter().flatten() {
+                *pending.get_mut(dependent).unwrap() += 1;
+            }
+        }
+
+        // Kahn's algorithm, one level at a time. The table has no cycles, so starting with the
+        // cells without pending references every dirty cell ends up in exactly one level
+        let mut to_update: Vec<Coord> =
+            dirty.iter().filter(|c| pending[*c] == 0).copied().collect();
+        let mut level_ends = vec![];
+        let mut start = 0;
+        while start < to_update.len() {
+            let end = to_update.len();
+            for i in start..end {
+                for dependent in referenced_by.get(&to_update[i]).into_iter().flatten() {
+                    let p = pending.get_mut(dependent).unwrap();
+                    *p -= 1;
+                    if *p == 0 {
+                        to_update.push(*dependent);
+                    }
+                }
             }
+            level_ends.push(end);
+            start = end;
+        }
+
+        CellUpdater {
+            to_update,
+            level_ends,
         }
     }
 
-    fn remove_duplicates(&mut self) {
-        // we need to remove duplicates in the right order
-        // if a cell appears in two places in the queue, then only the LAST appearance should be saved
-        // the ones before need to be deleted
+    /// The cells of `to_update`, level by level
+    fn levels(&self) -> impl Iterator<Item = &[Coord]> {
+        let starts = std::iter::once(0).chain(self.level_ends.iter().copied());
+        starts
+            .zip(&self.level_ends)
+            .map(move |(start, end)| &self.to_update[start..*end])
+    }
 
-        // slow way
-        let mut to_remove = Vec::<usize>::new();
-        for i in (0..self.to_update.len()).rev() {
-            let mut curr_index = i;
+    /// `changed` and all cells depending on them, directly or indirectly
+    fn mark_dirty(changed: &[Coord], referenced_by: &HashMap<Coord, Vec<Coord>>) -> Vec<Coord> {
+        let mut seen: HashSet<Coord> = HashSet::new();
+        let mut dirty: Vec<Coord> = changed
+            .iter()
+            .filter(|c| seen.insert(**c))
+            .copied()
+            .collect();
+        let mut stack = dirty.clone();
 
-            while let Some(new_pos) = self.to_update[0..curr_index]
-                .iter()
-                .rev()
-                .position(|cell| *cell == self.to_update[i])
-            {
-                // new_pos now index of reversed slice to_update[0..curr_index]
-                let new_pos = curr_index - 1 - new_pos;
-
-                if to_remove.iter().position(|&j| j == new_pos).is_none() {
-                    to_remove.push(new_pos);
+        while let Some(c) = stack.pop() {
+            for dependent in referenced_by.get(&c).into_iter().flatten() {
+                if seen.insert(*dependent) {
+                    dirty.push(*dependent);
+                    stack.push(*dependent);
                 }
-                curr_index = new_pos;
             }
         }
 
-        for i in &to_remove {
-            self.to_update.remove(*i);
-        }
+        dirty
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::cell::{Aggregate, CellError};
+    use crate::function;
+    use crate::parser::parse;
+
+    #[test]
+    fn update_order_is_topological() {
+        // (1, 1) -> (2, 2) -> (4, 4) -> (5, 5)
+        //        -> (3, 3) -> (4, 4)
+        //        -> (4, 4)
+        let referenced_by: HashMap<Coord, Vec<Coord>> = vec![
+            ((1, 1), vec![(4, 4), (2, 2), (3, 3)]),
+            ((2, 2), vec![(4, 4)]),
+            ((3, 3), vec![(4, 4)]),
+            ((4, 4), vec![(5, 5)]),
+            ((6, 6), vec![(4, 4)]),
+        ]
+        .into_iter()
+        .collect();
+
+        let updater = CellUpdater::new(&[(1, 1)], &referenced_by);
+        assert_eq!(
+            updater.levels().collect::<Vec<_>>(),
+            vec![
+                vec![(1, 1)],
+                vec![(2, 2), (3, 3)],
+                vec![(4, 4)],
+                vec![(5, 5)]
+            ]
+        );
+    }
 
     #[test]
-    fn test_remove_duplicates() {
-        let mut c = CellUpdater {
-            to_update: vec![(1, 1), (4, 4), (2, 2), (4, 4), (3, 3), (4, 4), (5, 5)],
+    fn update_long_chain() {
+        let mut table = Table::new();
+
+        table.insert((1, 1), Operation::Value(0.)).unwrap();
+        for y in 2..=10_000 {
+            table
+                .insert(
+                    (1, y),
+                    Operation::Add(
+                        Box::new(Operation::Cell((1, y - 1))),
+                        Box::new(Operation::Value(1.)),
+                    ),
+                )
+                .unwrap();
+        }
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+
+        assert_eq!(
+            table.get_value_of_cell(&(1, 10_000)),
+            Some(CellValue::Num(10_000.))
+        );
+    }
+
+    #[test]
+    fn update_wide_levels() {
+        let mut table = Table::new();
+
+        // enough independent cells per level to be evaluated in parallel
+        for x in 1..=1_000 {
+            table
+                .insert(
+                    (x, 2),
+                    Operation::Mul(
+                        Box::new(Operation::Cell((1, 1))),
+                        Box::new(Operation::Value(x as f64)),
+                    ),
+                )
+                .unwrap();
+            table
+                .insert(
+                    (x, 3),
+                    Operation::Add(
+                        Box::new(Operation::Cell((x, 2))),
+                        Box::new(Operation::Cell((1, 1))),
+                    ),
+                )
+                .unwrap();
+        }
+        table.insert((1, 1), Operation::Value(2.)).unwrap();
+
+        for x in 1..=1_000 {
+            let expected = 2. * x as f64 + 2.;
+            assert_eq!(
+                table.get_value_of_cell(&(x, 3)),
+                Some(CellValue::Num(expected))
+            );
+        }
+    }
+
+    #[test]
+    fn nested_operations() {
+        let mut table = Table::new();
+
+        // (1, 4) = ((1, 1) + (1, 2)) + (1, 3)
+        table
+            .insert(
+                (1, 4),
+                Operation::Add(
+                    Box::new(Operation::Add(
+                        Box::new(Operation::Cell((1, 1))),
+                        Box::new(Operation::Cell((1, 2))),
+                    )),
+                    Box::new(Operation::Cell((1, 3))),
+                ),
+            )
+            .unwrap();
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table.insert((1, 2), Operation::Value(2.)).unwrap();
+        table.insert((1, 3), Operation::Value(3.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(1, 4)), Some(CellValue::Num(6.)));
+
+        table.insert((1, 2), Operation::Value(-2.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(1, 4)), Some(CellValue::Num(2.)));
+    }
+
+    #[test]
+    fn arithmetic_operations() {
+        let mut table = Table::new();
+
+        table.insert((1, 1), Operation::Value(7.)).unwrap();
+        table.insert((1, 2), Operation::Value(2.)).unwrap();
+        let a1 = || Box::new(Operation::Cell((1, 1)));
+        let a2 = || Box::new(Operation::Cell((1, 2)));
+
+        table.insert((2, 1), Operation::Sub(a1(), a2())).unwrap();
+        table.insert((2, 2), Operation::Mul(a1(), a2())).unwrap();
+        table.insert((2, 3), Operation::Div(a1(), a2())).unwrap();
+        table.insert((2, 4), Operation::Pow(a1(), a2())).unwrap();
+        table.insert((2, 5), Operation::Mod(a1(), a2())).unwrap();
+        table.insert((2, 6), Operation::Neg(a1())).unwrap();
+
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(5.)));
+        assert_eq!(table.get_value_of_cell(&(2, 2)), Some(CellValue::Num(14.)));
+        assert_eq!(table.get_value_of_cell(&(2, 3)), Some(CellValue::Num(3.5)));
+        assert_eq!(table.get_value_of_cell(&(2, 4)), Some(CellValue::Num(49.)));
+        assert_eq!(table.get_value_of_cell(&(2, 5)), Some(CellValue::Num(1.)));
+        assert_eq!(table.get_value_of_cell(&(2, 6)), Some(CellValue::Num(-7.)));
+    }
+
+    #[test]
+    fn empty_operands_behave_like_unity() {
+        let mut table = Table::new();
+
+        table.insert((1, 1), Operation::Value(3.)).unwrap();
+        // (1, 2) stays empty
+        table
+            .insert(
+                (2, 1),
+                Operation::Mul(
+                    Box::new(Operation::Cell((1, 1))),
+                    Box::new(Operation::Cell((1, 2))),
+                ),
+            )
+            .unwrap();
+        table
+            .insert(
+                (2, 2),
+                Operation::Sub(
+                    Box::new(Operation::Cell((1, 2))),
+                    Box::new(Operation::Cell((1, 1))),
+                ),
+            )
+            .unwrap();
+
+        assert_eq!(table.get_value_of_cell(&(1, 2)), Some(CellValue::Unity));
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(3.)));
+        assert_eq!(table.get_value_of_cell(&(2, 2)), Some(CellValue::Num(-3.)));
+    }
+
+    #[test]
+    fn insert_cycle_is_rejected() {
+        let mut table = Table::new();
+
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table.insert((2, 1), Operation::Cell((1, 1))).unwrap();
+        table.insert((3, 1), Operation::Cell((2, 1))).unwrap();
+
+        assert_eq!(
+            table.insert((1, 1), Operation::Cell((3, 1))),
+            Err(TableError::Cycle(vec![(1, 1), (3, 1), (2, 1), (1, 1)]))
+        );
+        assert_eq!(
+            table.insert(
+                (4, 1),
+                Operation::Add(
+                    Box::new(Operation::Cell((4, 1))),
+                    Box::new(Operation::Value(1.))
+                )
+            ),
+            Err(TableError::Cycle(vec![(4, 1), (4, 1)]))
+        );
+
+        // the rest of the table is left intact
+        assert_eq!(table.get_value_of_cell(&(1, 1)), Some(CellValue::Num(1.)));
+        assert_eq!(table.references.get(&(1, 1)), Some(&vec![]));
+        table.insert((1, 1), Operation::Value(2.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(3, 1)), Some(CellValue::Num(2.)));
+    }
+
+    #[test]
+    fn range_size_is_limited() {
+        let mut table = Table::new();
+        let sum =
+            |from, to| Operation::Aggregate(Aggregate::Sum, vec![Operation::Range((from, to))]);
+
+        assert_eq!(
+            table.insert((1, 1), sum((2, 1), (27, 1_000_000))),
+            Err(TableError::RangesTooLarge(26_000_000))
+        );
+        assert!(table.ids.is_empty());
+        assert!(table.referenced_by.is_empty());
+        assert_eq!(
+            TableError::RangesTooLarge(26_000_000).to_string(),
+            "ranges span 26000000 cells, more than 100000"
+        );
+
+        // several ranges count together, however they are nested
+        let column = |x| Operation::Range(((x, 1), (x, 40_000)));
+        let several = Operation::Aggregate(
+            Aggregate::Sum,
+            vec![
+                column(2),
+                Operation::Aggregate(Aggregate::Max, vec![column(3), column(4)]),
+            ],
+        );
+        assert_eq!(
+            table.insert((1, 1), several),
+            Err(TableError::RangesTooLarge(120_000))
+        );
+        assert!(table.ids.is_empty());
+        let two = Operation::Aggregate(Aggregate::Sum, vec![column(2), column(3)]);
+        table.insert((1, 1), two).unwrap();
+
+        // the corners may be given in any order
+        assert_eq!(range_size(&(1, 100_000), &(1, 1)), MAX_RANGE_SIZE);
+        table.insert((2, 1), sum((1, 100_001), (1, 2))).unwrap();
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(0.)));
+    }
+
+    #[test]
+    fn display_cycle() {
+        assert_eq!(
+            TableError::Cycle(vec![(1, 1), (2, 3), (1, 1)]).to_string(),
+            "circular reference: A1 -> B3 -> A1"
+        );
+    }
+
+    #[test]
+    fn diamond_is_no_cycle() {
+        let mut table = Table::new();
+
+        // (2, 1) and (2, 2) both depend on (1, 1), (3, 1) depends on both
+        table.insert((2, 1), Operation::Cell((1, 1))).unwrap();
+        table.insert((2, 2), Operation::Cell((1, 1))).unwrap();
+        table
+            .insert(
+                (3, 1),
+                Operation::Add(
+                    Box::new(Operation::Cell((2, 1))),
+                    Box::new(Operation::Cell((2, 2))),
+                ),
+            )
+            .unwrap();
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+
+        assert_eq!(table.get_value_of_cell(&(3, 1)), Some(CellValue::Num(2.)));
+    }
+
+    #[test]
+    fn errors_propagate() {
+        let mut table = Table::new();
+
+        table.insert((1, 1), Operation::Value(0.)).unwrap();
+        table
+            .insert(
+                (2, 1),
+                Operation::Div(
+                    Box::new(Operation::Value(1.)),
+                    Box::new(Operation::Cell((1, 1))),
+                ),
+            )
+            .unwrap();
+        table
+            .insert(
+                (3, 1),
+                Operation::Add(
+                    Box::new(Operation::Cell((2, 1))),
+                    Box::new(Operation::Value(1.)),
+                ),
+            )
+            .unwrap();
+        table
+            .insert((4, 1), Operation::Error(CellError::Name))
+            .unwrap();
+
+        assert_eq!(
+            table.get_value_of_cell(&(2, 1)),
+            Some(CellValue::Error(CellError::Div0))
+        );
+        assert_eq!(
+            table.get_value_of_cell(&(3, 1)),
+            Some(CellValue::Error(CellError::Div0))
+        );
+        assert_eq!(
+            table.get_value_of_cell(&(4, 1)),
+            Some(CellValue::Error(CellError::Name))
+        );
+
+        table.insert((1, 1), Operation::Value(2.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(3, 1)), Some(CellValue::Num(1.5)));
+    }
+
+    #[test]
+    fn text_and_bool_values() {
+        let mut table = Table::new();
+
+        table
+            .insert((1, 1), Operation::Text("header".to_string()))
+            .unwrap();
+        table
+            .insert((1, 2), Operation::Text("2".to_string()))
+            .unwrap();
+        table.insert((1, 3), Operation::Bool(true)).unwrap();
+        table
+            .insert(
+                (2, 1),
+                Operation::Add(
+                    Box::new(Operation::Cell((1, 1))),
+                    Box::new(Operation::Value(1.)),
+                ),
+            )
+            .unwrap();
+        table
+            .insert(
+                (2, 2),
+                Operation::Add(
+                    Box::new(Operation::Cell((1, 2))),
+                    Box::new(Operation::Cell((1, 3))),
+                ),
+            )
+            .unwrap();
+
+        assert_eq!(
+            table.get_value_of_cell(&(1, 1)),
+            Some(CellValue::Text("header".to_string()))
+        );
+        assert_eq!(
+            table.get_value_of_cell(&(1, 3)),
+            Some(CellValue::Bool(true))
+        );
+        assert_eq!(
+            table.get_value_of_cell(&(2, 1)),
+            Some(CellValue::Error(CellError::Value))
+        );
+        assert_eq!(table.get_value_of_cell(&(2, 2)), Some(CellValue::Num(3.)));
+    }
+
+    #[test]
+    fn aggregates_over_ranges() {
+        let mut table = Table::new();
+        let agg = |a, from, to| Operation::Aggregate(a, vec![Operation::Range((from, to))]);
+
+        // the range (1, 1):(1, 4) is still empty
+        table
+            .insert((2, 1), agg(Aggregate::Sum, (1, 1), (1, 4)))
+            .unwrap();
+        table
+            .insert((2, 2), agg(Aggregate::Average, (1, 1), (1, 4)))
+            .unwrap();
+        table
+            .insert((2, 3), agg(Aggregate::Min, (1, 4), (1, 1)))
+            .unwrap();
+        table
+            .insert((2, 4), agg(Aggregate::Max, (1, 1), (1, 4)))
+            .unwrap();
+        table
+            .insert((2, 5), agg(Aggregate::Count, (1, 1), (1, 4)))
+            .unwrap();
+
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(0.)));
+        assert_eq!(
+            table.get_value_of_cell(&(2, 2)),
+            Some(CellValue::Error(CellError::Div0))
+        );
+        assert_eq!(table.get_value_of_cell(&(2, 5)), Some(CellValue::Num(0.)));
+
+        // filling the range updates the aggregates, text is ignored
+        table.insert((1, 1), Operation::Value(4.)).unwrap();
+        table.insert((1, 2), Operation::Value(-2.)).unwrap();
+        table
+            .insert((1, 3), Operation::Text("x".to_string()))
+            .unwrap();
+        table.insert((1, 4), Operation::Value(1.)).unwrap();
+
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(3.)));
+        assert_eq!(table.get_value_of_cell(&(2, 2)), Some(CellValue::Num(1.)));
+        assert_eq!(table.get_value_of_cell(&(2, 3)), Some(CellValue::Num(-2.)));
+        assert_eq!(table.get_value_of_cell(&(2, 4)), Some(CellValue::Num(4.)));
+        assert_eq!(table.get_value_of_cell(&(2, 5)), Some(CellValue::Num(3.)));
+
+        // errors propagate, except for COUNT
+        table
+            .insert((1, 3), Operation::Error(CellError::Ref))
+            .unwrap();
+        assert_eq!(
+            table.get_value_of_cell(&(2, 1)),
+            Some(CellValue::Error(CellError::Ref))
+        );
+        assert_eq!(table.get_value_of_cell(&(2, 5)), Some(CellValue::Num(3.)));
+    }
+
+    #[test]
+    fn aggregate_mixed_arguments() {
+        let mut table = Table::new();
+
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table.insert((2, 2), Operation::Value(2.)).unwrap();
+        table
+            .insert(
+                (3, 3),
+                Operation::Aggregate(
+                    Aggregate::Sum,
+                    vec![
+                        Operation::Range(((1, 1), (2, 2))),
+                        Operation::Text("3".to_string()),
+                        Operation::Bool(true),
+                    ],
+                ),
+            )
+            .unwrap();
+        table
+            .insert((4, 4), Operation::Range(((1, 1), (2, 2))))
+            .unwrap();
+
+        assert_eq!(table.get_value_of_cell(&(3, 3)), Some(CellValue::Num(7.)));
+        assert_eq!(
+            table.get_value_of_cell(&(4, 4)),
+            Some(CellValue::Error(CellError::Value))
+        );
+        assert_eq!(
+            table.insert(
+                (1, 2),
+                Operation::Aggregate(Aggregate::Sum, vec![Operation::Range(((3, 3), (3, 4)))])
+            ),
+            Err(TableError::Cycle(vec![(1, 2), (3, 3), (1, 2)]))
+        );
+    }
+
+    #[test]
+    fn function_calls() {
+        let mut table = Table::new();
+        let call = |name: &str, args| Operation::Call(name.to_string(), args);
+
+        table.insert((1, 1), Operation::Value(-4.)).unwrap();
+        table
+            .insert((2, 1), call("ABS", vec![Operation::Cell((1, 1))]))
+            .unwrap();
+        table
+            .insert((2, 2), call("SQRT", vec![Operation::Cell((1, 1))]))
+            .unwrap();
+        table
+            .insert((2, 3), call("FOO", vec![Operation::Cell((1, 1))]))
+            .unwrap();
+        table
+            .insert((2, 4), call("PI", vec![Operation::Value(1.)]))
+            .unwrap();
+        table
+            .insert((2, 5), Operation::Aggregate(Aggregate::Sum, vec![]))
+            .unwrap();
+
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(4.)));
+        assert_eq!(
+            table.get_value_of_cell(&(2, 2)),
+            Some(CellValue::Error(CellError::Num))
+        );
+        assert_eq!(
+            table.get_value_of_cell(&(2, 3)),
+            Some(CellValue::Error(CellError::Name))
+        );
+        assert_eq!(
+            table.get_value_of_cell(&(2, 4)),
+            Some(CellValue::Error(CellError::Value))
+        );
+        assert_eq!(
+            table.get_value_of_cell(&(2, 5)),
+            Some(CellValue::Error(CellError::Value))
+        );
+        // arguments of unknown functions are references nevertheless
+        assert_eq!(table.referenced_by.get(&(1, 1)).map(Vec::len), Some(3));
+
+        table.insert((1, 1), Operation::Value(9.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(2, 2)), Some(CellValue::Num(3.)));
+    }
+
+    #[test]
+    fn conditions() {
+        let mut table = Table::new();
+        let value = |table: &Table, input: &str| {
+            let mut table = table.clone();
+            table.insert((9, 9), parse(input).unwrap()).unwrap();
+            table.get_value_of_cell(&(9, 9)).unwrap()
+        };
+        let bool = CellValue::Bool;
+        let error = CellValue::Error;
+
+        table.insert((1, 1), Operation::Value(2.)).unwrap();
+        table
+            .insert((1, 2), Operation::Text("b".to_string()))
+            .unwrap();
+        table.insert((1, 3), Operation::Bool(true)).unwrap();
+        table
+            .insert((1, 4), Operation::Error(CellError::Div0))
+            .unwrap();
+
+        assert_eq!(value(&table, "=A1 >= 2"), bool(true));
+        assert_eq!(value(&table, "=A2 = \"B\""), bool(true));
+        assert_eq!(value(&table, "=A2 < 1"), bool(false));
+        assert_eq!(value(&table, "=A4 <> 1"), error(CellError::Div0));
+
+        assert_eq!(
+            value(&table, "=IF(A1 > 1, \"big\", A4)"),
+            CellValue::Text("big".to_string())
+        );
+        assert_eq!(value(&table, "=IF(A1 > 5, A4)"), bool(false));
+        assert_eq!(value(&table, "=IF(A2, 1, 2)"), error(CellError::Value));
+        assert_eq!(value(&table, "=IF(A5, 1, 2)"), CellValue::Num(2.));
+        assert_eq!(value(&table, "=IF(1)"), error(CellError::Value));
+
+        // text and empty cells in ranges are skipped, evaluation stops at the first FALSE
+        assert_eq!(value(&table, "=AND(A1:A3, A5:A6)"), bool(true));
+        assert_eq!(value(&table, "=AND(FALSE, A4)"), bool(false));
+        assert_eq!(value(&table, "=AND(A4, FALSE)"), error(CellError::Div0));
+        assert_eq!(value(&table, "=AND(A5:A6)"), error(CellError::Value));
+        assert_eq!(value(&table, "=OR(0, A1:A4)"), bool(true));
+        assert_eq!(value(&table, "=OR(0, \"false\")"), bool(false));
+        assert_eq!(value(&table, "=XOR(A1, A3)"), bool(false));
+        assert_eq!(value(&table, "=XOR(A1, A3, 1)"), bool(true));
+        assert_eq!(value(&table, "=NOT(A5)"), bool(true));
+
+        assert_eq!(value(&table, "=IFERROR(A4, A1)"), CellValue::Num(2.));
+        assert_eq!(
+            value(&table, "=IFERROR(A2, A4)"),
+            CellValue::Text("b".to_string())
+        );
+        assert_eq!(
+            value(&table, "=IFS(A1 = 1, 10, A1 = 2, 20, A4, 30)"),
+            CellValue::Num(20.)
+        );
+        assert_eq!(value(&table, "=IFS(A1 = 1, 10)"), error(CellError::NA));
+        assert_eq!(
+            value(&table, "=IFS(A1 = 1, 10, TRUE)"),
+            error(CellError::Value)
+        );
+        assert_eq!(
+            value(&table, "=SWITCH(A2, \"a\", 1, \"B\", 2, A4)"),
+            CellValue::Num(2.)
+        );
+        assert_eq!(value(&table, "=SWITCH(A1, 1, 1, 0)"), CellValue::Num(0.));
+        assert_eq!(value(&table, "=SWITCH(A1, 1, 1)"), error(CellError::NA));
+
+        // the referenced cells of branches which aren't taken are dependencies nevertheless
+        table
+            .insert((2, 1), parse("=IF(A1 > 2, A6, 0)").unwrap())
+            .unwrap();
+        table.insert((1, 6), Operation::Value(7.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(0.)));
+        table.insert((1, 1), Operation::Value(3.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(7.)));
+    }
+
+    #[test]
+    fn lookups() {
+        let mut table = Table::new();
+        let value = |table: &Table, input: &str| {
+            let mut table = table.clone();
+            table.insert((9, 9), parse(input).unwrap()).unwrap();
+            table.get_value_of_cell(&(9, 9)).unwrap()
         };
-        c.remove_duplicates();
+        let text = |s: &str| CellValue::Text(s.to_string());
+        let num = CellValue::Num;
+        let error = CellValue::Error;
+
+        // fruits with their price and stock, and discounts by sorted thresholds
+        for (y, (name, price, stock)) in [
+            ("Apple", 1.5, 10.),
+            ("Banana", 0.5, 20.),
+            ("Cherry", 3., 30.),
+        ]
+        .iter()
+        .enumerate()
+        {
+            let y = y as u32 + 1;
+            table
+                .insert((1, y), Operation::Text(name.to_string()))
+                .unwrap();
+            table.insert((2, y), Operation::Value(*price)).unwrap();
+            table.insert((3, y), Operation::Value(*stock)).unwrap();
+        }
+        for (y, (threshold, discount)) in [(0., "none"), (100., "silver"), (500., "gold")]
+            .iter()
+            .enumerate()
+        {
+            let y = y as u32 + 1;
+            table.insert((5, y), Operation::Value(*threshold)).unwrap();
+            table
+                .insert((6, y), Operation::Text(discount.to_string()))
+                .unwrap();
+            table
+                .insert((8, y), Operation::Value(13. - 4. * y as f64))
+                .unwrap();
+        }
+
+        assert_eq!(
+            value(&table, "=VLOOKUP(\"banana\", A1:C3, 2, FALSE)"),
+            num(0.5)
+        );
+        assert_eq!(
+            value(&table, "=VLOOKUP(\"ch*\", A1:C3, 3, FALSE)"),
+            num(30.)
+        );
+        assert_eq!(
+            value(&table, "=VLOOKUP(\"kiwi\", A1:C3, 2, FALSE)"),
+            error(CellError::NA)
+        );
+        assert_eq!(value(&table, "=VLOOKUP(250, E1:F3, 2)"), text("silver"));
+        assert_eq!(value(&table, "=VLOOKUP(500, E1:F3, 2, TRUE)"), text("gold"));
+        assert_eq!(
+            value(&table, "=VLOOKUP(-1, E1:F3, 2)"),
+            error(CellError::NA)
+        );
+        assert_eq!(
+            value(&table, "=VLOOKUP(\"apple\", A1:C3, 4, FALSE)"),
+            error(CellError::Ref)
+        );
+        assert_eq!(
+            value(&table, "=VLOOKUP(\"apple\", A1:C3, 0, FALSE)"),
+            error(CellError::Value)
+        );
+        assert_eq!(
+            value(&table, "=VLOOKUP(\"apple\", A1, 1)"),
+            error(CellError::Value)
+        );
+        assert_eq!(value(&table, "=HLOOKUP(0.5, B2:C3, 2, FALSE)"), num(3.));
+
+        assert_eq!(value(&table, "=INDEX(A1:C3, 3, 2)"), num(3.));
+        assert_eq!(value(&table, "=INDEX(A1:C1, 3)"), num(10.));
+        assert_eq!(value(&table, "=INDEX(A1:C3, 4, 1)"), error(CellError::Ref));
+
+        assert_eq!(value(&table, "=MATCH(\"CHERRY\", A1:A3, 0)"), num(3.));
+        assert_eq!(value(&table, "=MATCH(150, E1:E3)"), num(2.));
+        assert_eq!(value(&table, "=MATCH(6, H1:H3, -1)"), num(1.));
+        assert_eq!(value(&table, "=MATCH(150, A1:C3, 0)"), error(CellError::NA));
+        // numbers don't match text
+        assert_eq!(
+            value(&table, "=MATCH(\"10\", C1:C3, 0)"),
+            error(CellError::NA)
+        );
+
+        assert_eq!(
+            value(&table, "=XLOOKUP(\"banana\", A1:A3, C1:C3)"),
+            num(20.)
+        );
+        assert_eq!(
+            value(&table, "=XLOOKUP(\"kiwi\", A1:A3, C1:C3, \"-\")"),
+            text("-")
+        );
+        assert_eq!(
+            value(&table, "=XLOOKUP(25, C1:C3, A1:A3, 0, -1)"),
+            text("Banana")
+        );
+        assert_eq!(
+            value(&table, "=XLOOKUP(25, C1:C3, A1:A3, 0, 1)"),
+            text("Cherry")
+        );
+        assert_eq!(
+            value(&table, "=XLOOKUP(\"?an*\", A1:A3, B1:B3, 0, 2)"),
+            num(0.5)
+        );
+        assert_eq!(
+            value(&table, "=XLOOKUP(\"?an*\", A1:A3, B1:B3)"),
+            error(CellError::NA)
+        );
+
+        // indices and modes which aren't finite
+        table.insert((10, 1), Operation::Value(f64::NAN)).unwrap();
+        table
+            .insert((10, 2), Operation::Value(f64::INFINITY))
+            .unwrap();
+        for input in &[
+            "=INDEX(A1:C3, J1, 1)",
+            "=INDEX(A1:C3, 1, J2)",
+            "=INDEX(A1:C1, J1)",
+            "=VLOOKUP(\"apple\", A1:C3, J1, FALSE)",
+            "=HLOOKUP(0.5, B2:C3, J2, FALSE)",
+            "=MATCH(150, E1:E3, J1)",
+            "=XLOOKUP(25, C1:C3, A1:A3, 0, J1)",
+            "=XLOOKUP(25, C1:C3, A1:A3, 0, 0, J2)",
+        ] {
+            assert_eq!(value(&table, input), error(CellError::Value), "{}", input);
+        }
+        assert_eq!(
+            value(&table, "=XLOOKUP(\"*\", A1:A3, C1:C3, 0, 2, -1)"),
+            num(30.)
+        );
+        assert_eq!(
+            value(&table, "=XLOOKUP(10, C1:C3, A1:B3)"),
+            error(CellError::Value)
+        );
+
+        // the whole searched range is a dependency, including cells which are still empty
+        table
+            .insert(
+                (4, 5),
+                parse("=VLOOKUP(\"kiwi\", A1:C4, 2, FALSE)").unwrap(),
+            )
+            .unwrap();
+        assert_eq!(table.get_value_of_cell(&(4, 5)), Some(error(CellError::NA)));
+        assert!(table.referenced_by[&(3, 4)].contains(&(4, 5)));
+
+        table
+            .insert((1, 4), Operation::Text("Kiwi".to_string()))
+            .unwrap();
+        table.insert((2, 4), Operation::Value(2.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(4, 5)), Some(num(2.)));
+        table.insert((2, 4), Operation::Value(2.5)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(4, 5)), Some(num(2.5)));
+    }
+
+    #[test]
+    fn text_operations() {
+        let mut table = Table::new();
+        table.insert((1, 1), Operation::Value(2.5)).unwrap();
+        table
+            .insert((1, 2), Operation::Text(" Zoë ".to_string()))
+            .unwrap();
+        table
+            .insert((2, 1), parse("=A1 & \" kg\" & A3").unwrap())
+            .unwrap();
+        table
+            .insert((2, 2), parse("=UPPER(TRIM(A2)) & LEN(A2)").unwrap())
+            .unwrap();
+        table.insert((2, 3), parse("=A1 & #REF!").unwrap()).unwrap();
+
+        let text = |s: &str| Some(CellValue::Text(s.to_string()));
+        assert_eq!(table.get_value_of_cell(&(2, 1)), text("2.5 kg"));
+        assert_eq!(table.get_value_of_cell(&(2, 2)), text("ZOË5"));
+        assert_eq!(
+            table.get_value_of_cell(&(2, 3)),
+            Some(CellValue::Error(CellError::Ref))
+        );
+
+        // each cell doubles the text of the one above, until it gets too long
+        table
+            .insert((5, 1), Operation::Text("abcdefgh".to_string()))
+            .unwrap();
+        for y in 2..=14 {
+            let above = Box::new(Operation::Cell((5, y - 1)));
+            table
+                .insert((5, y), Operation::Concat(above.clone(), above))
+                .unwrap();
+        }
+        match table.get_value_of_cell(&(5, 12)) {
+            Some(CellValue::Text(s)) => assert_eq!(s.len(), 16384),
+            value => panic!("expected text, got {:?}", value),
+        }
+        for y in 13..=14 {
+            assert_eq!(
+                table.get_value_of_cell(&(5, y)),
+                Some(CellValue::Error(CellError::Value))
+            );
+        }
+
+        table.insert((1, 1), Operation::Bool(true)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(2, 1)), text("TRUE kg"));
+    }
+
+    #[test]
+    fn custom_functions() {
+        /// sum of the arguments times the factor
+        struct Scaled(f64);
+
+        impl Function for Scaled {
+            fn name(&self) -> &str {
+                "scaled"
+            }
+
+            fn arity(&self) -> function::Arity {
+                function::Arity { min: 1, max: None }
+            }
+
+            fn evaluate(&self, args: &[CellValue]) -> CellValue {
+                args.iter().fold(CellValue::Unity, |sum, v| sum + v.clone())
+                    * CellValue::Num(self.0)
+            }
+        }
+
+        let mut table = Table::new();
+        let call = |args| Operation::Call("SCALED".to_string(), args);
+
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table
+            .insert(
+                (2, 1),
+                call(vec![Operation::Cell((1, 1)), Operation::Value(2.)]),
+            )
+            .unwrap();
+        table.insert((3, 1), call(vec![])).unwrap();
+        assert_eq!(
+            table.get_value_of_cell(&(2, 1)),
+            Some(CellValue::Error(CellError::Name))
+        );
+
+        // registering resolves the existing calls
+        table.register_function(Arc::new(Scaled(10.))).unwrap();
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(30.)));
+        assert_eq!(
+            table.get_value_of_cell(&(3, 1)),
+            Some(CellValue::Error(CellError::Value))
+        );
+
+        // custom functions take part in recalculation and cycle detection
+        table.insert((1, 1), Operation::Value(2.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(40.)));
+        assert!(table
+            .insert((1, 1), call(vec![Operation::Cell((2, 1))]))
+            .is_err());
+
+        // clones keep their functions
+        let mut clone = table.clone();
+        clone.register_function(Arc::new(Scaled(-1.))).unwrap();
+        assert_eq!(clone.get_value_of_cell(&(2, 1)), Some(CellValue::Num(-4.)));
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(40.)));
+    }
+
+    #[test]
+    fn reserved_functions() {
+        struct Constant(&'static str);
+
+        impl Function for Constant {
+            fn name(&self) -> &str {
+                self.0
+            }
+
+            fn arity(&self) -> function::Arity {
+                function::Arity { min: 0, max: None }
+            }
+
+            fn evaluate(&self, _: &[CellValue]) -> CellValue {
+                CellValue::Num(7.)
+            }
+        }
+
+        let mut table = Table::new();
+        table.insert((1, 1), parse("=SUM(1, 2)").unwrap()).unwrap();
+        table.insert((1, 2), parse("=ABS(-2)").unwrap()).unwrap();
+
+        // functions parsed into their own operations can't be replaced
+        for name in &["sum", "IF", "Xlookup"] {
+            assert_eq!(
+                table.register_function(Arc::new(Constant(name))),
+                Err(TableError::ReservedFunction(name.to_string()))
+            );
+        }
+        assert_eq!(table.get_value_of_cell(&(1, 1)), Some(CellValue::Num(3.)));
+
+        table.register_function(Arc::new(Constant("abs"))).unwrap();
+        assert_eq!(table.get_value_of_cell(&(1, 2)), Some(CellValue::Num(7.)));
+    }
+
+    #[test]
+    fn cells_of_range() {
+        assert_eq!(
+            cells_in_range(&(2, 2), &(1, 1)),
+            vec![(1, 1), (2, 1), (1, 2), (2, 2)]
+        );
+        assert_eq!(cells_in_range(&(3, 4), &(3, 4)), vec![(3, 4)]);
+    }
+
+    #[test]
+    fn update_order_multiple_changed() {
+        // (1, 1) -> (2, 2) -> (3, 3)
+        let referenced_by: HashMap<Coord, Vec<Coord>> =
+            vec![((1, 1), vec![(2, 2)]), ((2, 2), vec![(3, 3)])]
+                .into_iter()
+                .collect();
+
+        let updater = CellUpdater::new(&[(2, 2), (1, 1), (2, 2)], &referenced_by);
+        assert_eq!(updater.to_update, vec![(1, 1), (2, 2), (3, 3)]);
+        assert_eq!(updater.level_ends, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn remove_cells() {
+        let mut table = Table::new();
+
+        table.insert((1, 1), Operation::Value(2.)).unwrap();
+        table.insert((1, 2), Operation::Value(3.)).unwrap();
+        table
+            .insert(
+                (2, 1),
+                Operation::Mul(
+                    Box::new(Operation::Cell((1, 1))),
+                    Box::new(Operation::Cell((1, 2))),
+                ),
+            )
+            .unwrap();
+
+        // dependents see the removed cell as empty
+        table.remove(&(1, 1));
+        assert_eq!(table.get_value_of_cell(&(1, 1)), Some(CellValue::Unity));
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(3.)));
+
+        // cells nobody references are gone completely, including the emptied (1, 1)
+        table.remove(&(2, 1));
+        assert_eq!(table.get_value_of_cell(&(2, 1)), None);
+        assert_eq!(table.get_value_of_cell(&(1, 1)), None);
+        assert_eq!(table.references.get(&(2, 1)), None);
+        assert_eq!(table.referenced_by.get(&(1, 1)), None);
+        assert_eq!(table.referenced_by.get(&(1, 2)), Some(&vec![]));
+
+        table.remove(&(1, 2));
+        table.remove(&(9, 9));
+        assert_eq!(table.iter().count(), 0);
+    }
+
+    #[test]
+    fn unreferenced_empty_cells_are_dropped() {
+        let mut table = Table::new();
+
+        table.insert((2, 1), Operation::Cell((1, 1))).unwrap();
+        assert_eq!(table.get_value_of_cell(&(1, 1)), Some(CellValue::Unity));
+
+        table.insert((2, 1), Operation::Value(1.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(1, 1)), None);
+        assert_eq!(table.iter().count(), 1);
+    }
+
+    #[test]
+    fn clear_range_of_cells() {
+        let mut table = Table::new();
+
+        for y in 1..=3 {
+            table.insert((1, y), Operation::Value(y as f64)).unwrap();
+        }
+        table
+            .insert(
+                (2, 1),
+                Operation::Aggregate(Aggregate::Sum, vec![Operation::Range(((1, 1), (1, 3)))]),
+            )
+            .unwrap();
+
+        table.clear_range(&(1, 3), &(1, 2));
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(1.)));
+        assert_eq!(table.get_value_of_cell(&(1, 2)), Some(CellValue::Unity));
+
+        table.clear_range(&(1, 1), &(2, 3));
+        assert_eq!(table.iter().count(), 0);
+        assert!(table.references.is_empty());
+        assert!(table.referenced_by.is_empty());
+    }
+
+    #[test]
+    fn references_of_nested_operation() {
+        let op = Operation::Add(
+            Box::new(Operation::Call(
+                "SIN".to_string(),
+                vec![Operation::Cell((2, 2))],
+            )),
+            Box::new(Operation::Add(
+                Box::new(Operation::Cell((1, 1))),
+                Box::new(Operation::Cell((2, 2))),
+            )),
+        );
+        assert_eq!(op.references(), vec![(2, 2), (1, 1)]);
+    }
+
+    #[test]
+    fn overwrite_removes_references() {
+        let mut table = Table::new();
+
+        table
+            .insert(
+                (2, 1),
+                Operation::Add(
+                    Box::new(Operation::Cell((1, 1))),
+                    Box::new(Operation::Value(1.)),
+                ),
+            )
+            .unwrap();
+        table.insert((2, 1), Operation::Cell((1, 2))).unwrap();
+
+        assert_eq!(table.references.get(&(2, 1)), Some(&vec![(1, 2)]));
+        assert_eq!(table.referenced_by.get(&(1, 1)), None);
+        assert_eq!(table.referenced_by.get(&(1, 2)), Some(&vec![(2, 1)]));
+    }
+
+    #[test]
+    fn operations_of_cells() {
+        let mut table = Table::new();
 
-        assert_eq!(c.to_update, vec![(1, 1), (2, 2), (3, 3), (4, 4), (5, 5)])
+        let op = Operation::Add(
+            Box::new(Operation::Cell((1, 1))),
+            Box::new(Operation::Value(1.)),
+        );
+        table.insert((2, 1), op).unwrap();
+
+        assert_eq!(
+            table.get_operation_of_cell(&(2, 1)).map(|o| o.to_string()),
+            Some("A1+1".to_string())
+        );
+        // referenced, but empty
+        assert_eq!(table.get_operation_of_cell(&(1, 1)), None);
+
+        table.remove(&(2, 1));
+        assert_eq!(table.get_operation_of_cell(&(2, 1)), None);
+    }
+
+    #[test]
+    fn undo_and_redo() {
+        let mut table = Table::new();
+
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table
+            .insert(
+                (1, 2),
+                Operation::Mul(
+                    Box::new(Operation::Cell((1, 1))),
+                    Box::new(Operation::Value(2.)),
+                ),
+            )
+            .unwrap();
+        table.insert((1, 1), Operation::Value(5.)).unwrap();
+        table.remove(&(1, 2));
+        // failed inserts are no change
+        assert!(table.insert((1, 1), Operation::Cell((1, 1))).is_err());
+
+        assert!(table.undo());
+        assert_eq!(table.get_value_of_cell(&(1, 2)), Some(CellValue::Num(10.)));
+        assert!(table.undo());
+        assert_eq!(table.get_value_of_cell(&(1, 2)), Some(CellValue::Num(2.)));
+
+        assert!(table.redo());
+        assert_eq!(table.get_value_of_cell(&(1, 2)), Some(CellValue::Num(10.)));
+
+        assert!(table.undo());
+        assert!(table.undo());
+        assert!(table.undo());
+        assert!(!table.undo());
+        assert_eq!(table.get_value_of_cell(&(1, 1)), None);
+        assert!(table.ids.is_empty());
+
+        assert!(table.redo());
+        assert_eq!(table.get_value_of_cell(&(1, 1)), Some(CellValue::Num(1.)));
+
+        // a new change drops everything which could be redone
+        table.insert((2, 1), Operation::Value(3.)).unwrap();
+        assert!(!table.redo());
+    }
+
+    #[test]
+    fn undo_transactions() {
+        let mut table = Table::new();
+
+        table.begin_transaction();
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table.begin_transaction();
+        table.insert((2, 1), Operation::Value(2.)).unwrap();
+        table.end_transaction();
+        table.insert((3, 1), Operation::Value(3.)).unwrap();
+        table.end_transaction();
+
+        table.clear_range(&(1, 1), &(2, 1));
+        assert_eq!(table.get_operation_of_cell(&(1, 1)), None);
+
+        assert!(table.undo());
+        assert_eq!(table.get_value_of_cell(&(1, 1)), Some(CellValue::Num(1.)));
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(2.)));
+
+        assert!(table.undo());
+        assert!(table.ids.is_empty());
+        assert!(!table.undo());
+
+        assert!(table.redo());
+        assert_eq!(table.get_value_of_cell(&(3, 1)), Some(CellValue::Num(3.)));
+    }
+
+    #[test]
+    fn undo_paste() {
+        let mut table = Table::new();
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table.insert((2, 2), Operation::Value(2.)).unwrap();
+
+        table
+            .paste(
+                (1, 1),
+                vec![
+                    vec![Some(Operation::Value(3.)), Some(Operation::Cell((1, 1)))],
+                    vec![None, None],
+                ],
+            )
+            .unwrap();
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(3.)));
+        assert_eq!(table.get_operation_of_cell(&(2, 2)), None);
+
+        assert!(table.undo());
+        assert_eq!(table.get_value_of_cell(&(1, 1)), Some(CellValue::Num(1.)));
+        assert_eq!(table.get_operation_of_cell(&(2, 1)), None);
+        assert_eq!(table.get_value_of_cell(&(2, 2)), Some(CellValue::Num(2.)));
+        assert!(table.undo());
+        assert!(table.redo());
+        assert!(table.redo());
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(3.)));
+
+        // a cycle leaves the table unchanged
+        let before = table.operations.clone();
+        let result = table.paste(
+            (1, 3),
+            vec![
+                vec![Some(Operation::Value(4.)), Some(Operation::Cell((1, 4)))],
+                vec![Some(Operation::Cell((2, 3)))],
+            ],
+        );
+        assert_eq!(result, Err(TableError::Cycle(vec![(1, 4), (2, 3), (1, 4)])));
+        assert_eq!(table.operations, before);
+        assert!(table.undo());
+        assert_eq!(table.get_operation_of_cell(&(2, 1)), None);
+    }
+
+    #[test]
+    fn insert_rows() {
+        let mut table = Table::new();
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table.insert((1, 2), Operation::Value(2.)).unwrap();
+        table.insert((1, 3), Operation::Value(3.)).unwrap();
+        table.insert((2, 1), Operation::Cell((1, 3))).unwrap();
+        table
+            .insert(
+                (2, 2),
+                Operation::Aggregate(Aggregate::Sum, vec![Operation::Range(((1, 1), (1, 3)))]),
+            )
+            .unwrap();
+        table.set_format((1, 3), Format::Percent(0));
+
+        table.insert_rows(2, 2).unwrap();
+        assert_eq!(table.get_operation_of_cell(&(1, 2)), None);
+        assert_eq!(table.get_value_of_cell(&(1, 4)), Some(CellValue::Num(2.)));
+        assert_eq!(
+            table.get_operation_of_cell(&(2, 1)),
+            Some(&Operation::Cell((1, 5)))
+        );
+        assert_eq!(
+            table.get_operation_of_cell(&(2, 4)),
+            Some(&Operation::Aggregate(
+                Aggregate::Sum,
+                vec![Operation::Range(((1, 1), (1, 5)))]
+            ))
+        );
+        assert_eq!(table.get_value_of_cell(&(2, 4)), Some(CellValue::Num(6.)));
+        assert_eq!(table.get_format(&(1, 5)), Format::Percent(0));
+        assert_eq!(table.get_format(&(1, 3)), Format::General);
+
+        assert!(table.undo());
+        assert_eq!(table.get_value_of_cell(&(1, 2)), Some(CellValue::Num(2.)));
+        assert_eq!(
+            table.get_operation_of_cell(&(2, 1)),
+            Some(&Operation::Cell((1, 3)))
+        );
+        assert_eq!(table.get_value_of_cell(&(2, 2)), Some(CellValue::Num(6.)));
+        assert_eq!(table.get_operation_of_cell(&(1, 5)), None);
+        assert!(table.redo());
+        assert_eq!(table.get_value_of_cell(&(2, 1)), Some(CellValue::Num(3.)));
+
+        table.insert((1, u32::MAX), Operation::Value(1.)).unwrap();
+        assert_eq!(table.insert_rows(1, 1), Err(TableError::RowOverflow));
+    }
+
+    #[test]
+    fn history_is_bounded() {
+        let mut table = Table::new();
+        table.set_history_limit(3);
+
+        for i in 0..10 {
+            table.insert((1, 1), Operation::Value(i as f64)).unwrap();
+        }
+        for _ in 0..3 {
+            assert!(table.undo());
+        }
+        assert!(!table.undo());
+        assert_eq!(table.get_value_of_cell(&(1, 1)), Some(CellValue::Num(6.)));
+
+        table.clear_history();
+        assert!(!table.redo());
+    }
+
+    #[test]
+    fn tables_are_send_sync_and_clone() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Table>();
+
+        let mut table = Table::new();
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table.insert((1, 2), Operation::Cell((1, 1))).unwrap();
+
+        let snapshot = table.clone();
+        table.insert((1, 1), Operation::Value(2.)).unwrap();
+        assert_eq!(table.get_value_of_cell(&(1, 2)), Some(CellValue::Num(2.)));
+        assert_eq!(
+            snapshot.get_value_of_cell(&(1, 2)),
+            Some(CellValue::Num(1.))
+        );
+
+        // the clone recalculates on its own
+        let mut snapshot = snapshot;
+        snapshot.insert((1, 1), Operation::Value(3.)).unwrap();
+        assert_eq!(
+            snapshot.get_value_of_cell(&(1, 2)),
+            Some(CellValue::Num(3.))
+        );
+        assert_eq!(table.get_value_of_cell(&(1, 2)), Some(CellValue::Num(2.)));
+    }
+
+    #[test]
+    fn observe_changed_values() {
+        use std::sync::Mutex;
+
+        let mut table = Table::new();
+        let events = Arc::new(Mutex::new(vec![]));
+        let log = events.clone();
+        table.set_observer(Some(Arc::new(move |changed: &[Coord]| {
+            log.lock().unwrap().push(changed.to_vec())
+        })));
+
+        table.insert((1, 1), Operation::Value(1.)).unwrap();
+        table.insert((1, 2), Operation::Cell((1, 1))).unwrap();
+        table
+            .insert(
+                (1, 3),
+                Operation::Mul(
+                    Box::new(Operation::Cell((1, 2))),
+                    Box::new(Operation::Value(0.)),
+                ),
+            )
+            .unwrap();
+        table.insert((1, 1), Operation::Value(2.)).unwrap();
+        // same value, nothing to report
+        table.insert((1, 1), Operation::Value(2.)).unwrap();
+        table.remove(&(1, 3));
+        table.remove(&(1, 1));
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                vec![(1, 1)],
+                vec![(1, 2)],
+                vec![(1, 3)],
+                // (1, 3) stays 0
+                vec![(1, 1), (1, 2)],
+                vec![(1, 3)],
+                vec![(1, 1), (1, 2)],
+            ]
+        );
+
+        table.set_observer(None);
+        table.insert((1, 1), Operation::Value(3.)).unwrap();
+        assert_eq!(events.lock().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn removed_cells_free_their_slots() {
+        let mut table = Table::new();
+
+        for _ in 0..3 {
+            table.insert((1, 1), Operation::Value(1.)).unwrap();
+            table.insert((2, 1), Operation::Cell((1, 1))).unwrap();
+            table.remove(&(1, 1));
+            table.remove(&(2, 1));
+        }
+        assert!(table.ids.is_empty());
+        assert_eq!(table.iter().count(), 0);
     }
 }
@@ -0,0 +1,470 @@
+//! Text functions, e.g. `=LEFT(A1, 3) & "..."`.
+//!
+//! | function                            | result                                                |
+//! |-------------------------------------|-------------------------------------------------------|
+//! | `CONCAT(a, b, ...)`                 | text of all arguments joined                          |
+//! | `LEN(text)`                         | number of characters                                  |
+//! | `LEFT(text, [n])`, `RIGHT`          | first or last `n` characters, `n` defaults to 1       |
+//! | `MID(text, start, n)`               | `n` characters from position `start`                  |
+//! | `UPPER`, `LOWER`                    | text in upper or lower case                           |
+//! | `TRIM(text)`                        | text without leading, trailing and repeated spaces    |
+//! | `SUBSTITUTE(text, old, new, [nth])` | text with every, or only the `nth`, `old` replaced    |
+//! | `FIND(find, text, [start])`         | position of `find` in `text`, from position `start`   |
+//! | `SEARCH(find, text, [start])`       | like `FIND`, ignoring case and with wildcards         |
+//! | `TEXT(value, format)`               | `value` displayed in `format`, see `format::Format`   |
+//! | `VALUE(text)`                       | number written in `text`                              |
+//! | `REPT(text, n)`                     | text repeated `n` times                               |
+//!
+//! Characters are grapheme clusters, i.e. what a reader perceives as a single character,
+//! so `LEN("e\u{301}")` (an `e` with a combining accent) is 1. Positions start at 1.
+//! Numbers are formatted as displayed, empty cells are empty text.
+//! `FIND` and `SEARCH` result in `#VALUE!` if the text isn't found.
+//! In patterns of `SEARCH` `?` matches any character, `*` any number of characters
+//! and `~` escapes the next character.
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::cell::{parse_number, CellError, CellValue};
+use crate::format::Format;
+use crate::function::{Arity, Function};
+
+/// Longest text a function or `&` may produce, in bytes
+pub const MAX_LENGTH: usize = 32767;
+
+/// Function on text built into tabcel
+#[derive(Clone, Copy)]
+pub(crate) struct TextFunction {
+    name: &'static str,
+    arity: Arity,
+    apply: fn(&[CellValue]) -> Result<CellValue, CellError>,
+}
+
+impl Function for TextFunction {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn arity(&self) -> Arity {
+        self.arity
+    }
+
+    fn evaluate(&self, args: &[CellValue]) -> CellValue {
+        match (self.apply)(args) {
+            Ok(CellValue::Text(s)) if s.len() > MAX_LENGTH => CellValue::Error(CellError::Value),
+            Ok(value) => value,
+            Err(e) => CellValue::Error(e),
+        }
+    }
+}
+
+fn graphemes(s: &str) -> Vec<&str> {
+    s.graphemes(true).collect()
+}
+
+/// Argument `i` as text
+fn text(args: &[CellValue], i: usize) -> Result<String, CellError> {
+    args[i].to_text()
+}
+
+/// Optional argument `i` as a count or position truncated towards zero, `#VALUE!` if it is
+/// less than `min`
+fn count(args: &[CellValue], i: usize, default: usize, min: usize) -> Result<usize, CellError> {
+    let n = match args.get(i) {
+        Some(arg) => arg.to_number()?.trunc(),
+        None => return Ok(default),
+    };
+    if !n.is_finite() || n < min as f64 {
+        return Err(CellError::Value);
+    }
+    Ok(n as usize)
+}
+
+/// Whether `pattern` matches the beginning of `text`, or all of it if `whole`.
+/// `?` matches one grapheme, `*` any number of them and `~` escapes the next grapheme.
+/// Takes at most `pattern.len() * text.len()` steps, however many `*` there are
+pub fn wildcard_match(pattern: &[&str], text: &[&str], whole: bool) -> bool {
+    // `None` stands for `?`, `Some(g)` for the grapheme `g`, with `*` separating the parts
+    let mut parts: Vec<Vec<Option<&str>>> = vec![vec![]];
+    let mut graphemes = pattern.iter();
+    while let Some(&g) = graphemes.next() {
+        let part = parts.last_mut().unwrap();
+        match g {
+            "*" => parts.push(vec![]),
+            "?" => part.push(None),
+            "~" => part.push(Some(graphemes.next().copied().unwrap_or("~"))),
+            g => part.push(Some(g)),
+        }
+    }
+    let matches = |part: &[Option<&str>], at: usize| {
+        text.len() - at >= part.len()
+            && part
+                .iter()
+                .zip(&text[at..])
+                .all(|(p, g)| p.map_or(true, |p| p == *g))
+    };
+
+    // the first part has to match at the start, every other part as early as possible after
+    // the previous one, which leaves the most text to the following parts
+    let (first, rest) = parts.split_first().unwrap();
+    if !matches(first, 0) {
+        return false;
+    }
+    let mut at = first.len();
+    let last = match rest.split_last() {
+        Some((last, middle)) => {
+            for part in middle {
+                match (at..=text.len()).find(|&i| matches(part, i)) {
+                    Some(i) => at = i + part.len(),
+                    None => return false,
+                }
+            }
+            last
+        }
+        None => return !whole || at == text.len(),
+    };
+    if whole {
+        // the last part has to match at the end
+        text.len() - at >= last.len() && matches(last, text.len() - last.len())
+    } else {
+        (at..=text.len()).any(|i| matches(last, i))
+    }
+}
+
+/// Whether all of `text` matches `pattern`, ignoring case, see `wildcard_match`
+pub fn matches_pattern(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.to_lowercase(), text.to_lowercase());
+    wildcard_match(&graphemes(&pattern), &graphemes(&text), true)
+}
+
+/// Position of `find` in `within` for `FIND` and `SEARCH`, using `matches` to compare
+fn position<M>(args: &[CellValue], case_sensitive: bool, matches: M) -> Result<CellValue, CellError>
+where
+    M: Fn(&[&str], &[&str]) -> bool,
+{
+    let (mut find, mut within) = (text(args, 0)?, text(args, 1)?);
+    if !case_sensitive {
+        find = find.to_lowercase();
+        within = within.to_lowercase();
+    }
+    let (find, within) = (graphemes(&find), graphemes(&within));
+    let start = count(args, 2, 1, 1)?;
+    if start > within.len() + 1 {
+        return Err(CellError::Value);
+    }
+
+    (start - 1..=within.len())
+        .find(|i| matches(&find, &within[*i..]))
+        .map(|i| CellValue::Num((i + 1) as f64))
+        .ok_or(CellError::Value)
+}
+
+fn substitute(args: &[CellValue]) -> Result<CellValue, CellError> {
+    let (text, old, new) = (text(args, 0)?, text(args, 1)?, text(args, 2)?);
+    let nth = match args.get(3) {
+        Some(_) => Some(count(args, 3, 1, 1)?),
+        None => None,
+    };
+    let (text, old) = (graphemes(&text), graphemes(&old));
+    if old.is_empty() {
+        return Ok(CellValue::Text(text.concat()));
+    }
+
+    let mut result = String::new();
+    let mut found = 0;
+    let mut i = 0;
+    while i < text.len() {
+        if text[i..].starts_with(&old) {
+            found += 1;
+            if nth.map_or(true, |n| n == found) {
+                result.push_str(&new);
+                i += old.len();
+                continue;
+            }
+        }
+        result.push_str(text[i]);
+        i += 1;
+    }
+    Ok(CellValue::Text(result))
+}
+
+fn text_format(args: &[CellValue]) -> Result<CellValue, CellError> {
+    let format = text(args, 1)?
+        .parse::<Format>()
+        .map_err(|_| CellError::Value)?;
+    let value = match &args[0] {
+        CellValue::Error(e) => return Err(*e),
+        CellValue::Text(s) => parse_number(s).map_or(args[0].clone(), CellValue::Num),
+        value => value.clone(),
+    };
+    Ok(CellValue::Text(format.apply(&value)))
+}
+
+pub(crate) static FUNCTIONS: &[TextFunction] = &[
+    TextFunction {
+        name: "CONCAT",
+        arity: Arity { min: 1, max: None },
+        apply: |a| {
+            let texts: Result<Vec<String>, CellError> = a.iter().map(|v| v.to_text()).collect();
+            Ok(CellValue::Text(texts?.concat()))
+        },
+    },
+    TextFunction {
+        name: "LEN",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(CellValue::Num(graphemes(&text(a, 0)?).len() as f64)),
+    },
+    TextFunction {
+        name: "LEFT",
+        arity: Arity::between(1, 2),
+        apply: |a| {
+            let (text, n) = (text(a, 0)?, count(a, 1, 1, 0)?);
+            Ok(CellValue::Text(text.graphemes(true).take(n).collect()))
+        },
+    },
+    TextFunction {
+        name: "RIGHT",
+        arity: Arity::between(1, 2),
+        apply: |a| {
+            let (text, n) = (text(a, 0)?, count(a, 1, 1, 0)?);
+            let graphemes = graphemes(&text);
+            let start = graphemes.len().saturating_sub(n);
+            Ok(CellValue::Text(graphemes[start..].concat()))
+        },
+    },
+    TextFunction {
+        name: "MID",
+        arity: Arity::exactly(3),
+        apply: |a| {
+            let (text, start, n) = (text(a, 0)?, count(a, 1, 1, 1)?, count(a, 2, 0, 0)?);
+            Ok(CellValue::Text(
+                text.graphemes(true).skip(start - 1).take(n).collect(),
+            ))
+        },
+    },
+    TextFunction {
+        name: "UPPER",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(CellValue::Text(text(a, 0)?.to_uppercase())),
+    },
+    TextFunction {
+        name: "LOWER",
+        arity: Arity::exactly(1),
+        apply: |a| Ok(CellValue::Text(text(a, 0)?.to_lowercase())),
+    },
+    TextFunction {
+        name: "TRIM",
+        arity: Arity::exactly(1),
+        apply: |a| {
+            let text = text(a, 0)?;
+            let words: Vec<&str> = text.split(' ').filter(|w| !w.is_empty()).collect();
+            Ok(CellValue::Text(words.join(" ")))
+        },
+    },
+    TextFunction {
+        name: "SUBSTITUTE",
+        arity: Arity::between(3, 4),
+        apply: substitute,
+    },
+    TextFunction {
+        name: "FIND",
+        arity: Arity::between(2, 3),
+        apply: |a| position(a, true, |find, within| within.starts_with(find)),
+    },
+    TextFunction {
+        name: "SEARCH",
+        arity: Arity::between(2, 3),
+        apply: |a| position(a, false, |find, within| wildcard_match(find, within, false)),
+    },
+    TextFunction {
+        name: "TEXT",
+        arity: Arity::exactly(2),
+        apply: text_format,
+    },
+    TextFunction {
+        name: "VALUE",
+        arity: Arity::exactly(1),
+        apply: |a| match &a[0] {
+            CellValue::Bool(_) => Err(CellError::Value),
+            value => value.to_number().map(CellValue::Num),
+        },
+    },
+    TextFunction {
+        name: "REPT",
+        arity: Arity::exactly(2),
+        apply: |a| {
+            let (text, n) = (text(a, 0)?, count(a, 1, 0, 0)?);
+            if text.len().saturating_mul(n) > MAX_LENGTH {
+                return Err(CellError::Value);
+            }
+            Ok(CellValue::Text(text.repeat(n)))
+        },
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::function::Functions;
+
+    fn call(name: &str, args: &[CellValue]) -> CellValue {
+        crate::function::call(&**Functions::new().get(name).unwrap(), args)
+    }
+
+    fn t(s: &str) -> CellValue {
+        CellValue::Text(s.to_string())
+    }
+
+    #[test]
+    fn text_functions() {
+        let n = CellValue::Num;
+        assert_eq!(
+            call(
+                "CONCAT",
+                &[t("a"), n(1.5), CellValue::Unity, CellValue::Bool(true)]
+            ),
+            t("a1.5TRUE")
+        );
+        assert_eq!(call("LEN", &[t("")]), n(0.));
+        assert_eq!(call("LEFT", &[t("abc")]), t("a"));
+        assert_eq!(call("LEFT", &[t("abc"), n(5.)]), t("abc"));
+        assert_eq!(call("RIGHT", &[t("abc"), n(2.)]), t("bc"));
+        assert_eq!(call("MID", &[t("abcdef"), n(2.), n(3.)]), t("bcd"));
+        assert_eq!(call("MID", &[t("abc"), n(5.), n(3.)]), t(""));
+        assert_eq!(call("UPPER", &[t("straße")]), t("STRASSE"));
+        assert_eq!(call("LOWER", &[t("ÄB")]), t("äb"));
+        assert_eq!(call("TRIM", &[t("  a   b ")]), t("a b"));
+        assert_eq!(
+            call("SUBSTITUTE", &[t("a-b-c"), t("-"), t("+")]),
+            t("a+b+c")
+        );
+        assert_eq!(
+            call("SUBSTITUTE", &[t("a-b-c"), t("-"), t(""), n(2.)]),
+            t("a-bc")
+        );
+        assert_eq!(call("FIND", &[t("b"), t("abcb"), n(3.)]), n(4.));
+        assert_eq!(
+            call("FIND", &[t("B"), t("abc")]),
+            CellValue::Error(CellError::Value)
+        );
+        assert_eq!(call("SEARCH", &[t("B?D"), t("abcd")]), n(2.));
+        assert_eq!(call("SEARCH", &[t("c*"), t("abc")]), n(3.));
+        assert_eq!(call("SEARCH", &[t("~*"), t("a*b")]), n(2.));
+        assert_eq!(call("TEXT", &[n(0.256), t("percent 1")]), t("25.6%"));
+        assert_eq!(call("TEXT", &[t(" 2"), t("fixed 2")]), t("2.00"));
+        assert_eq!(call("VALUE", &[t(" 1e3 ")]), n(1000.));
+        assert_eq!(call("REPT", &[t("ab"), n(2.9)]), t("abab"));
+    }
+
+    #[test]
+    fn graphemes_are_characters() {
+        // `e` with a combining accent, a family emoji of several code points and a flag
+        let s = "e\u{301}\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}\u{1F1E9}\u{1F1EA}x";
+        assert_eq!(call("LEN", &[t(s)]), CellValue::Num(4.));
+        assert_eq!(call("LEFT", &[t(s)]), t("e\u{301}"));
+        assert_eq!(
+            call("RIGHT", &[t(s), CellValue::Num(2.)]),
+            t("\u{1F1E9}\u{1F1EA}x")
+        );
+        assert_eq!(
+            call("MID", &[t(s), CellValue::Num(2.), CellValue::Num(1.)]),
+            t("\u{1F468}\u{200D}\u{1F469}\u{200D}\u{1F467}")
+        );
+        assert_eq!(call("FIND", &[t("x"), t(s)]), CellValue::Num(4.));
+        // a lone `e` doesn't match the accented one
+        assert_eq!(
+            call("SUBSTITUTE", &[t("e\u{301}e"), t("e"), t("a")]),
+            t("e\u{301}a")
+        );
+        assert_eq!(call("SEARCH", &[t("?x"), t(s)]), CellValue::Num(3.));
+    }
+
+    #[test]
+    fn wildcards() {
+        for (pattern, text) in &[
+            ("", ""),
+            ("*", ""),
+            ("a*", "abc"),
+            ("*c", "abc"),
+            ("a*b*c", "aXbYbZc"),
+            ("a?c", "ABC"),
+            ("*?", "a"),
+            ("~*b", "*b"),
+            ("a~", "a~"),
+        ] {
+            assert!(
+                matches_pattern(pattern, text),
+                "{} doesn't match {}",
+                pattern,
+                text
+            );
+        }
+        // only the beginning
+        for (pattern, text) in &[("ab", "abc"), ("a*c", "abcd"), ("a*b*", "ab")] {
+            let (pattern, text) = (graphemes(pattern), graphemes(text));
+            assert!(wildcard_match(&pattern, &text, false));
+        }
+        for (pattern, text) in &[
+            ("", "a"),
+            ("a*", "ba"),
+            ("*a", "ab"),
+            ("a*b*c", "acb"),
+            ("a?c", "ac"),
+            ("~*b", "ab"),
+            ("ab*ba", "aba"),
+        ] {
+            assert!(
+                !matches_pattern(pattern, text),
+                "{} matches {}",
+                pattern,
+                text
+            );
+        }
+
+        // many stars don't make matching exponential
+        let pattern = format!("{}b", "*a".repeat(20));
+        let text = "a".repeat(MAX_LENGTH);
+        assert!(!matches_pattern(&pattern, &text));
+        assert!(matches_pattern(&pattern, &(text + "b")));
+    }
+
+    #[test]
+    fn text_errors() {
+        let value = CellValue::Error(CellError::Value);
+        assert_eq!(call("LEFT", &[t("abc"), CellValue::Num(-1.)]), value);
+        assert_eq!(
+            call("MID", &[t("abc"), CellValue::Num(0.), CellValue::Num(1.)]),
+            value
+        );
+        assert_eq!(call("FIND", &[t("a"), t("abc"), CellValue::Num(5.)]), value);
+        assert_eq!(
+            call("SUBSTITUTE", &[t("a"), t("a"), t("b"), CellValue::Num(0.)]),
+            value
+        );
+        assert_eq!(call("TEXT", &[CellValue::Num(1.), t("fancy")]), value);
+        assert_eq!(call("VALUE", &[t("one")]), value);
+        assert_eq!(call("TEXT", &[t("NaN"), t("fixed 1")]), t("NaN"));
+        assert_eq!(call("VALUE", &[CellValue::Bool(true)]), value);
+        assert_eq!(call("REPT", &[t("ab"), CellValue::Num(1e9)]), value);
+        assert_eq!(call("LEN", &[]), value);
+        assert_eq!(
+            call("TEXT", &[CellValue::Num(1.), t("fixed 100000")]),
+            value
+        );
+
+        // counts which aren't finite
+        for n in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let n = CellValue::Num(*n);
+            let one = CellValue::Num(1.);
+            assert_eq!(call("MID", &[t("abc"), n.clone(), one.clone()]), value);
+            assert_eq!(call("MID", &[t("abc"), one, n.clone()]), value);
+            assert_eq!(call("FIND", &[t("a"), t("abc"), n.clone()]), value);
+            assert_eq!(call("SEARCH", &[t("a"), t("abc"), n.clone()]), value);
+            assert_eq!(call("LEFT", &[t("abc"), n.clone()]), value);
+            assert_eq!(call("REPT", &[t("ab"), n]), value);
+        }
+        assert_eq!(
+            call("CONCAT", &[t("a"), CellValue::Error(CellError::Ref)]),
+            CellValue::Error(CellError::Ref)
+        );
+    }
+}